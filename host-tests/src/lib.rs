@@ -0,0 +1,184 @@
+//! Mocks of the hardware used by the host tests of `lets-split`.
+
+use core::{cell::Cell, convert::Infallible, ops::Range};
+use embedded_hal::{
+    blocking::delay::DelayUs,
+    digital::v2::{InputPin, OutputPin},
+};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use lets_split::matrix::{DiodeDirection, Matrix, MatrixConfig, Pull};
+
+// The library logs with defmt, logs are dropped on the host
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("");
+
+/// Flash with NOR semantics: writes can only clear bits, erasing sets them back
+pub struct MockFlash {
+    pub bytes: [u8; 256],
+    pub erases: usize,
+}
+
+impl MockFlash {
+    pub fn new() -> Self {
+        Self {
+            bytes: [0xff; 256],
+            erases: 0,
+        }
+    }
+}
+
+impl Default for MockFlash {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReadNorFlash for MockFlash {
+    type Error = ();
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), ()> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl NorFlash for MockFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 128;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), ()> {
+        self.bytes[from as usize..to as usize].fill(0xff);
+        self.erases += 1;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), ()> {
+        for (slot, byte) in self.bytes[offset as usize..].iter_mut().zip(bytes) {
+            *slot &= byte;
+        }
+        Ok(())
+    }
+}
+
+/// Matrix of 2 rows and 3 columns with a diode per key. Lines 0 and 1 are the rows, 2 to 4
+/// the columns.
+pub struct MockWiring {
+    config: MatrixConfig,
+    keys: Cell<[[bool; 3]; 2]>,
+    /// Level driven on each line, `None` for the inputs
+    driven: Cell<[Option<bool>; 5]>,
+}
+
+impl MockWiring {
+    pub fn new(config: MatrixConfig) -> Self {
+        Self {
+            config,
+            keys: Cell::new([[false; 3]; 2]),
+            driven: Cell::new([None; 5]),
+        }
+    }
+
+    pub fn press(&self, row: usize, col: usize, pressed: bool) {
+        let mut keys = self.keys.get();
+        keys[row][col] = pressed;
+        self.keys.set(keys);
+    }
+
+    fn lines(&self, lines: Range<usize>) -> impl Iterator<Item = MockPin<'_>> {
+        lines.map(move |line| MockPin(self, line))
+    }
+
+    /// Builds a matrix with the inputs and outputs expected by the configuration
+    pub fn matrix(&self) -> Matrix<MockPin<'_>, MockPin<'_>, 2, 3> {
+        if self.config.rows_driven() {
+            Matrix::new(self.config, self.lines(2..5), self.lines(0..2))
+        } else {
+            Matrix::new(self.config, self.lines(0..2), self.lines(2..5))
+        }
+    }
+
+    /// Level of a line, pulled unless a pressed key conducts from or to a driven line
+    pub fn level(&self, line: usize) -> bool {
+        let driven = self.driven.get();
+        if let Some(level) = driven[line] {
+            return level;
+        }
+        let pulled_up = self.config.pull == Pull::Up;
+        let row_anode = self.config.diodes == DiodeDirection::Row2Col;
+        for (row, keys) in self.keys.get().iter().enumerate() {
+            for (col, _) in keys.iter().enumerate().filter(|(_, &pressed)| pressed) {
+                let (other, anode) = match line {
+                    _ if line == row => (2 + col, row_anode),
+                    _ if line == 2 + col => (row, !row_anode),
+                    _ => continue,
+                };
+                match driven[other] {
+                    Some(false) if pulled_up && anode => return false,
+                    Some(true) if !pulled_up && !anode => return true,
+                    _ => {}
+                }
+            }
+        }
+        pulled_up
+    }
+}
+
+pub struct MockPin<'a>(&'a MockWiring, usize);
+
+impl MockPin<'_> {
+    fn drive(&self, level: bool) {
+        let mut driven = self.0.driven.get();
+        driven[self.1] = Some(level);
+        self.0.driven.set(driven);
+    }
+}
+
+impl InputPin for MockPin<'_> {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(self.0.level(self.1))
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(!self.0.level(self.1))
+    }
+}
+
+impl OutputPin for MockPin<'_> {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.drive(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.drive(true);
+        Ok(())
+    }
+}
+
+/// Counts the microseconds waited by the matrix scan
+pub struct MockDelay(pub u32);
+
+impl DelayUs<u32> for MockDelay {
+    fn delay_us(&mut self, us: u32) {
+        self.0 += us;
+    }
+}
@@ -0,0 +1,171 @@
+//! Key matrix scanning, against a simulated matrix for each wiring.
+
+use lets_split::{
+    matrix::{self, DiodeDirection, IdleTimer, MatrixConfig, Pull},
+    position::{MatrixEvent, MatrixPosition},
+};
+use lets_split_host_tests::{MockDelay, MockWiring};
+
+#[test]
+fn matrix_sleeps_until_a_key_is_pressed() {
+    let wiring = MockWiring::new(MatrixConfig::default());
+    let mut matrix = wiring.matrix();
+    let mut delay = MockDelay(0);
+    assert!(matrix.scan(&mut delay).is_empty());
+    // Each row settles once selected
+    assert_eq!(delay.0, 2);
+
+    wiring.press(1, 2, true);
+    let events = matrix.scan(&mut delay);
+    // The row of the pressed key also settles once unselected
+    assert_eq!(delay.0, 2 + 2 + 30);
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0],
+        MatrixEvent {
+            position: MatrixPosition::new(1, 2),
+            pressed: true
+        }
+    );
+    assert!(matrix.any_pressed());
+    wiring.press(1, 2, false);
+    assert_eq!(matrix.scan(&mut delay).len(), 1);
+    assert!(!matrix.any_pressed());
+
+    let mut idle = IdleTimer::new(100);
+    assert!(!idle.update(1_000, false));
+    assert!(!idle.update(1_050, true));
+    assert!(!idle.update(1_060, false));
+    assert!(idle.update(1_160, false));
+
+    // Every row is driven low while sleeping, any key pulls its column
+    matrix.sleep();
+    assert!((2..5).all(|col| wiring.level(col)));
+    wiring.press(0, 1, true);
+    assert!(!wiring.level(3));
+    delay.0 = 0;
+    let events = matrix.scan(&mut delay);
+    // The rows settle once unselected after sleeping
+    assert_eq!(delay.0, 30 + 2 + 30);
+    assert_eq!(matrix.max_scan_us(), 30 + 2 * (1 + 30));
+    assert!(delay.0 <= matrix.max_scan_us());
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].position, MatrixPosition::new(0, 1));
+
+    assert_eq!(matrix::exti_lines([4, 3, 2, 9, 8, 7]), Some(0x39c));
+    assert_eq!(matrix::exti_lines([6, 5, 4, 5, 4, 3]), None);
+}
+
+#[test]
+fn matrix_reads_every_wiring() {
+    for &diodes in &[DiodeDirection::Col2Row, DiodeDirection::Row2Col] {
+        for &pull in &[Pull::Up, Pull::Down] {
+            let wiring = MockWiring::new(MatrixConfig {
+                diodes,
+                pull,
+                ..MatrixConfig::default()
+            });
+            let mut matrix = wiring.matrix();
+            let mut delay = MockDelay(0);
+            assert!(matrix.scan(&mut delay).is_empty());
+
+            // Keys sharing a row and a column, no ghost at (0, 0) thanks to the diodes
+            wiring.press(1, 2, true);
+            wiring.press(1, 0, true);
+            wiring.press(0, 2, true);
+            let mut keys = [MatrixPosition::new(0, 0); 3];
+            let events = matrix.scan(&mut delay);
+            assert_eq!(events.len(), 3);
+            for (key, event) in keys.iter_mut().zip(&events) {
+                assert!(event.pressed);
+                *key = event.position;
+            }
+            keys.sort_unstable();
+            assert_eq!(
+                keys,
+                [
+                    MatrixPosition::new(0, 2),
+                    MatrixPosition::new(1, 0),
+                    MatrixPosition::new(1, 2)
+                ]
+            );
+
+            wiring.press(1, 0, false);
+            let events = matrix.scan(&mut delay);
+            assert_eq!(events.len(), 1);
+            assert_eq!(
+                events[0],
+                MatrixEvent {
+                    position: MatrixPosition::new(1, 0),
+                    pressed: false
+                }
+            );
+        }
+    }
+}
+
+#[test]
+fn matrix_holds_back_possible_ghosts() {
+    let wiring = MockWiring::new(MatrixConfig {
+        ghost_filter: true,
+        ..MatrixConfig::default()
+    });
+    let mut matrix = wiring.matrix();
+    let mut delay = MockDelay(0);
+    wiring.press(0, 0, true);
+    wiring.press(0, 1, true);
+    wiring.press(1, 0, true);
+    assert_eq!(matrix.scan(&mut delay).len(), 3);
+
+    // Read like the ghost of a matrix without diodes
+    wiring.press(1, 1, true);
+    assert!(matrix.scan(&mut delay).is_empty());
+    assert!(matrix.scan(&mut delay).is_empty());
+
+    wiring.press(0, 0, false);
+    let events = matrix.scan(&mut delay);
+    assert_eq!(
+        events.as_slice(),
+        [
+            MatrixEvent {
+                position: MatrixPosition::new(0, 0),
+                pressed: false
+            },
+            MatrixEvent {
+                position: MatrixPosition::new(1, 1),
+                pressed: true
+            }
+        ]
+    );
+}
+
+#[test]
+fn matrix_reports_rectangles_pressed_at_once() {
+    // Keys appearing in the same scan are not ghosts of each other
+    let wiring = MockWiring::new(MatrixConfig {
+        ghost_filter: true,
+        ..MatrixConfig::default()
+    });
+    let mut matrix = wiring.matrix();
+    let mut delay = MockDelay(0);
+    for (row, col) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+        wiring.press(row, col, true);
+    }
+    assert_eq!(matrix.scan(&mut delay).len(), 4);
+
+    // Without the filter a key completing a rectangle is reported right away
+    let wiring = MockWiring::new(MatrixConfig::default());
+    let mut matrix = wiring.matrix();
+    wiring.press(0, 0, true);
+    wiring.press(0, 1, true);
+    wiring.press(1, 0, true);
+    assert_eq!(matrix.scan(&mut delay).len(), 3);
+    wiring.press(1, 1, true);
+    assert_eq!(
+        matrix.scan(&mut delay).as_slice(),
+        [MatrixEvent {
+            position: MatrixPosition::new(1, 1),
+            pressed: true
+        }]
+    );
+}
@@ -0,0 +1,75 @@
+//! Flash store and settings, against an in-memory flash.
+
+use lets_split::{
+    keymap::{Action, Keymap},
+    settings::Settings,
+    side::Side,
+    storage::Store,
+};
+use lets_split_host_tests::MockFlash;
+
+const KEYMAP: Keymap<1, 2, 2> = Keymap::new([
+    [[Action::Key(0x04), Action::MomentaryLayer(1)]],
+    [[Action::Key(0x1e), Action::Transparent]],
+]);
+
+#[test]
+fn store_keeps_last_record_and_wraps() {
+    let mut flash = MockFlash::new();
+    let mut store = Store::new(128, 128);
+    let mut buf = [0; 16];
+    assert_eq!(store.load(&mut flash, &mut buf), Ok(None));
+
+    // 12 bytes header + 10 bytes payload padded to 24 bytes, 5 records per region
+    for i in 0..5u8 {
+        assert_eq!(store.fits(&mut flash, 10), Ok(true));
+        store.save(&mut flash, &[i; 10]).unwrap();
+    }
+    assert_eq!(store.fits(&mut flash, 10), Ok(false));
+    assert_eq!(flash.erases, 0);
+    store.save(&mut flash, &[5; 10]).unwrap();
+    assert_eq!(flash.erases, 1);
+    assert_eq!(flash.bytes[..128], [0xff; 128]);
+
+    let mut store = Store::new(128, 128);
+    assert_eq!(store.load(&mut flash, &mut buf), Ok(Some(10)));
+    assert_eq!(buf[..10], [5; 10]);
+}
+
+#[test]
+fn store_skips_corrupt_records() {
+    let mut flash = MockFlash::new();
+    let mut store = Store::new(0, 128);
+    let mut buf = [0; 16];
+    store.load(&mut flash, &mut buf).unwrap();
+    store.save(&mut flash, &[1; 8]).unwrap();
+    store.save(&mut flash, &[2; 8]).unwrap();
+    flash.bytes[20 + 12] ^= 0x01;
+
+    assert_eq!(store.load(&mut flash, &mut buf), Ok(Some(8)));
+    assert_eq!(buf[..8], [1; 8]);
+
+    // Garbage in the region is erased on the next save
+    flash.bytes[40] = 0x00;
+    assert_eq!(store.load(&mut flash, &mut buf), Ok(Some(8)));
+    store.save(&mut flash, &[3; 8]).unwrap();
+    assert_eq!(flash.erases, 1);
+    assert_eq!(store.load(&mut flash, &mut buf), Ok(Some(8)));
+    assert_eq!(buf[..8], [3; 8]);
+}
+
+#[test]
+fn settings_roundtrip() {
+    let mut settings: Settings<1, 2, 2> = Settings::new(KEYMAP);
+    settings.via.layout_options = 0x0102_0304;
+    settings.side = Some(Side::Right);
+
+    let mut buf = [0; 13];
+    let bytes = settings.encode(&mut buf);
+    assert_eq!(bytes.len(), Settings::<1, 2, 2>::SIZE);
+    assert_eq!(Settings::decode(bytes), Some(settings));
+
+    // Transparent on the base layer
+    buf[1] = 0x01;
+    assert_eq!(Settings::<1, 2, 2>::decode(&buf), None);
+}
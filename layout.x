@@ -0,0 +1,13 @@
+/* Build time checks of the flash partitions defined in memory.x, linked after link.x */
+ASSERT(LOADADDR(.data) + SIZEOF(.data) <= ORIGIN(FLASH) + LENGTH(FLASH),
+  "ERROR(lets-split): the firmware overflows the application slot");
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) <= ORIGIN(UPGRADE),
+  "ERROR(lets-split): the application slot overlaps the upgrade slot");
+ASSERT(ORIGIN(UPGRADE) + LENGTH(UPGRADE) <= ORIGIN(HEATMAP),
+  "ERROR(lets-split): the upgrade slot overlaps the heatmap store");
+ASSERT(ORIGIN(HEATMAP) + LENGTH(HEATMAP) <= ORIGIN(SETTINGS),
+  "ERROR(lets-split): the heatmap store overlaps the settings store");
+ASSERT(LENGTH(UPGRADE) >= LENGTH(FLASH),
+  "ERROR(lets-split): the upgrade slot cannot hold the application");
+ASSERT(ORIGIN(SETTINGS) + LENGTH(SETTINGS) <= 0x08080000,
+  "ERROR(lets-split): the settings store is outside the flash");
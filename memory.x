@@ -1,10 +1,28 @@
+/* Flash partitions of the STM32F411CE: 512K of flash in sectors of 16K (0-3), 64K (4) and
+   128K (5-7), 128K of RAM. Regions must start and end on sector boundaries. */
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
-  RAM : ORIGIN = 0x20000000, LENGTH = 32K
+  /* Sectors 0-4: application */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 128K
+  /* Sector 5: upgrade slot, where a second stage bootloader can stage a new application. The
+     firmware resets into the DFU bootloader in system memory, so the slot is unused by default */
+  UPGRADE : ORIGIN = 0x08020000, LENGTH = 128K
+  /* Sector 6: key press counters, see `lets_split::heatmap` */
+  HEATMAP : ORIGIN = 0x08040000, LENGTH = 128K
+  /* Sector 7: settings store, see `lets_split::storage` */
+  SETTINGS : ORIGIN = 0x08060000, LENGTH = 128K
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
 }
 
+/* Partitions, read by `lets_split::layout` */
+__upgrade_start = ORIGIN(UPGRADE);
+__upgrade_end = ORIGIN(UPGRADE) + LENGTH(UPGRADE);
+__heatmap_start = ORIGIN(HEATMAP);
+__heatmap_end = ORIGIN(HEATMAP) + LENGTH(HEATMAP);
+__settings_start = ORIGIN(SETTINGS);
+__settings_end = ORIGIN(SETTINGS) + LENGTH(SETTINGS);
+
 /* This is where the call stack will be allocated. */
 /* The stack is of the full descending type. */
 /* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
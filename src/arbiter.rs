@@ -0,0 +1,136 @@
+//! Bus arbitration of the single-wire half-duplex link.
+//!
+//! Both halves share one data line, so only the half holding the token transmits. The primary
+//! half starts with the token, keeps it for at least a poll period and then passes it with a
+//! [`Message::Token`]. The secondary half sends what it has queued and passes the token back
+//! right away. The primary takes the token back if the other half does not return it in time,
+//! for example when it is not connected.
+//!
+//! A half transmitting also receives its own bytes. The transport drops them by comparing them
+//! with the bytes it sent, so the arbiter only sees the frames of the other half.
+//!
+//! On a full-duplex link the arbiter always lets the half send.
+
+use crate::link::Message;
+
+/// Timing of the token passing, in microseconds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArbiterConfig {
+    /// Shortest time the primary half keeps the token
+    pub poll_us: u32,
+    /// Time after which the primary half takes back a token not returned
+    pub timeout_us: u32,
+}
+
+impl Default for ArbiterConfig {
+    fn default() -> Self {
+        Self {
+            poll_us: 500,
+            timeout_us: 5_000,
+        }
+    }
+}
+
+/// What a half may do with the bus
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Turn {
+    /// Send the queued frames
+    Send,
+    /// Send a [`Message::Token`] and nothing else
+    PassToken,
+    /// Keep quiet
+    Wait,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Link in full-duplex mode
+    FullDuplex,
+    /// Holding the token since the given time
+    Holding { since: u32 },
+    /// The other half holds the token since the given time, or it was passed then
+    Waiting { since: u32 },
+}
+
+/// Decides when a half may transmit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Arbiter {
+    config: ArbiterConfig,
+    primary: bool,
+    state: State,
+}
+
+impl Arbiter {
+    /// Arbiter of a full-duplex link, always letting the half send
+    pub const fn full_duplex() -> Self {
+        Self {
+            config: ArbiterConfig {
+                poll_us: 0,
+                timeout_us: 0,
+            },
+            primary: true,
+            state: State::FullDuplex,
+        }
+    }
+
+    /// Arbiter of a half-duplex link, the primary half holds the token at startup
+    pub const fn half_duplex(primary: bool, config: ArbiterConfig) -> Self {
+        Self {
+            config,
+            primary,
+            state: if primary {
+                State::Holding { since: 0 }
+            } else {
+                State::Waiting { since: 0 }
+            },
+        }
+    }
+
+    /// Returns true if the half receives the bytes it sends, on a single wire
+    pub const fn echoes(&self) -> bool {
+        !matches!(self.state, State::FullDuplex)
+    }
+
+    /// Returns what the half may do at `now_us`, `idle` if it has nothing queued.
+    ///
+    /// Must be called only when the previous transmission is complete, the half passes the
+    /// token when it answers [`Turn::PassToken`].
+    pub fn poll(&mut self, now_us: u32, idle: bool) -> Turn {
+        match self.state {
+            State::FullDuplex => Turn::Send,
+            State::Holding { since } => {
+                if idle && (!self.primary || now_us.wrapping_sub(since) >= self.config.poll_us) {
+                    self.state = State::Waiting { since: now_us };
+                    Turn::PassToken
+                } else {
+                    Turn::Send
+                }
+            }
+            State::Waiting { since } => {
+                if self.primary && now_us.wrapping_sub(since) > self.config.timeout_us {
+                    defmt::debug!("Token not returned, taking it back");
+                    self.state = State::Holding { since: now_us };
+                    Turn::Send
+                } else {
+                    Turn::Wait
+                }
+            }
+        }
+    }
+
+    /// Handles a frame of the other half received at `now_us`, returns true if it is not a
+    /// token.
+    ///
+    /// Frames are accepted whatever the state, the other half may still be sending after the
+    /// primary took the token back.
+    pub fn receive(&mut self, message: &Message, now_us: u32) -> bool {
+        if *message != Message::Token {
+            return true;
+        }
+        // A token returned after the primary took it back is stale
+        if let State::Waiting { .. } = self.state {
+            self.state = State::Holding { since: now_us };
+        }
+        false
+    }
+}
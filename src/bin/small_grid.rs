@@ -3,25 +3,54 @@
 
 use lets_split as _; // global logger + panicking-behavior + memory layout
 
-#[rtic::app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [ TIM4 ])]
+/// Builds the `KeyMatrix` of the given row and column pins, the inputs and outputs depending
+/// on the `MATRIX` configuration
+macro_rules! key_matrix {
+    (rows: [$($row:expr),* $(,)?], cols: [$($col:expr),* $(,)?] $(,)?) => {{
+        let pull = match MATRIX.pull {
+            Pull::Up => gpio::Pull::Up,
+            Pull::Down => gpio::Pull::Down,
+        };
+        if MATRIX.rows_driven() {
+            KeyMatrix::new(
+                MATRIX,
+                [$($col.into_input().internal_resistor(pull).erase()),*],
+                [$($row.into_push_pull_output().erase()),*],
+            )
+        } else {
+            KeyMatrix::new(
+                MATRIX,
+                [$($row.into_input().internal_resistor(pull).erase()),*],
+                [$($col.into_push_pull_output().erase()),*],
+            )
+        }
+    }};
+}
+
+#[rtic::app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [ TIM3, TIM4 ])]
 mod app {
     use core::convert::Infallible;
 
     use defmt::info;
+    use dwt_systick_monotonic::DwtSystick;
+    use embedded_hal::blocking::delay::DelayUs;
     use keyboard_io::{
-        buttons::{Button, ButtonAction, ButtonStatusEvent, GridState, LocalGrid},
+        buttons::{Button, ButtonAction, GridState},
         codes::KeyboardCode,
         // debouncer::DebouncedPin,
         hid::keyboard::{KeyboardReport, LedStatus},
         prelude::*,
     };
+    use lets_split::{
+        matrix::{self, DiodeDirection, IdleTimer, Matrix, MatrixConfig, Pull},
+        position::{HalfMap, KeyEvent},
+        report::ReportQueue,
+    };
     use stm32f4xx_hal::{
-        gpio::{alt, EPin, Input, Output, PushPull},
-        interrupt,
+        gpio::{self, alt, EPin, Edge, ExtiPin, Input, Output, PinExt, PushPull},
         otg_fs::{UsbBusType, USB},
-        pac,
+        pac::EXTI,
         prelude::*,
-        timer,
     };
     use usb_device::test_class::{PID, VID};
 
@@ -30,10 +59,71 @@ mod app {
     // type DebouncedInputPin = DebouncedPin<EPin<Input<PullUp>>>;
     type InputPin = EPin<Input>;
     type OutputPin = EPin<Output<PushPull>>;
+    type KeyMatrix = Matrix<InputPin, OutputPin, 2, 2>;
+
+    /// Core clock, also the frequency of the monotonic timer
+    const SYSCLK_HZ: u32 = 84_000_000;
+
+    #[monotonic(binds = SysTick, default = true)]
+    type Mono = DwtSystick<SYSCLK_HZ>;
+    type Instant = <Mono as rtic::Monotonic>::Instant;
+    type Duration = <Mono as rtic::Monotonic>::Duration;
+
+    /// Busy wait counted in core cycles, for the settle times of the matrix
+    struct SpinDelay;
+
+    impl DelayUs<u32> for SpinDelay {
+        fn delay_us(&mut self, us: u32) {
+            cortex_m::asm::delay(us * (SYSCLK_HZ / 1_000_000));
+        }
+    }
+
+    /// Milliseconds since boot at `instant`, wrapping around
+    fn millis(instant: Instant) -> u32 {
+        instant.duration_since_epoch().to_millis() as u32
+    }
+
+    /// Lets the given EXTI lines wake the matrix
+    fn enable_wake(lines: u32) {
+        let exti = unsafe { &*EXTI::ptr() };
+        // Edges seen while scanning are stale
+        exti.pr.write(|w| unsafe { w.bits(lines) });
+        exti.imr.modify(|r, w| unsafe { w.bits(r.bits() | lines) });
+    }
+
+    /// Masks the wake interrupts and resumes the matrix scan if it sleeps
+    fn resume_scan() {
+        // The wake lines are the only EXTI lines in use
+        let exti = unsafe { &*EXTI::ptr() };
+        exti.imr.write(|w| unsafe { w.bits(0) });
+        let pending = exti.pr.read().bits();
+        exti.pr.write(|w| unsafe { w.bits(pending) });
+        // Fails if the scan is already scheduled
+        scan::spawn(monotonics::now()).ok();
+    }
+
+    /// Period of the matrix scan
+    const SCAN_PERIOD_US: u32 = 250;
+
+    /// Wiring of the key matrix
+    const MATRIX: MatrixConfig = MatrixConfig {
+        diodes: DiodeDirection::Col2Row,
+        pull: Pull::Up,
+        select_delay_us: 1,
+        unselect_delay_us: 30,
+        ghost_filter: false,
+    };
+
+    /// Time without pressed key after which the matrix stops scanning until a key is pressed
+    const MATRIX_IDLE_MS: u32 = 1_000;
+
+    /// The grid is a single half, so its keys keep their matrix coordinates
+    const HALF_MAP: HalfMap<2, 2> = HalfMap::offset(0);
 
     // Shared resources go here
     #[shared]
     struct Shared {
+        reports: ReportQueue<KeyboardReport, 8>,
         status_grid: GridState<KeyboardCode, 2, 2, 0>,
         usb_dev: UsbDevice,
         usb_class: UsbKeyboardClass,
@@ -42,17 +132,16 @@ mod app {
     // Local resources go here
     #[local]
     struct Local {
-        local_grid: LocalGrid<InputPin, OutputPin, 2, 2>,
-        timer: timer::CounterUs<pac::TIM3>,
+        matrix: KeyMatrix,
+        /// EXTI lines of the matrix inputs
+        wake_lines: u32,
     }
 
     #[init(local = [
       usb_allocator: Option<UsbBusAllocator<UsbBusType>> = None,
       ep_memory: [u32; 1024] = [0; 1024],
     ])]
-    fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
-        rtic::pend(interrupt::TIM3);
-
+    fn init(mut c: init::Context) -> (Shared, Local, init::Monotonics) {
         let usb_allocator = c.local.usb_allocator;
         let ep_memory = c.local.ep_memory;
 
@@ -64,9 +153,12 @@ mod app {
             .require_pll48clk()
             .freeze();
 
-        let mut timer = c.device.TIM3.counter_us(&clocks);
-        timer.start(100.micros()).unwrap();
-        timer.listen(timer::Event::Update);
+        let mono = DwtSystick::new(
+            &mut c.core.DCB,
+            c.core.DWT,
+            c.core.SYST,
+            clocks.sysclk().raw(),
+        );
 
         let gpioa = c.device.GPIOA.split();
         let gpiob = c.device.GPIOB.split();
@@ -75,6 +167,9 @@ mod app {
         let mut led = gpioc.pc13.into_push_pull_output();
         led.set_low();
 
+        // Keeps the debug probe and RTT working while idle sleeps
+        c.device.DBGMCU.cr.modify(|_, w| w.dbg_sleep().set_bit());
+
         let usb = USB {
             usb_global: c.device.OTG_FS_GLOBAL,
             usb_device: c.device.OTG_FS_DEVICE,
@@ -93,38 +188,55 @@ mod app {
             .serial_number(env!("CARGO_PKG_VERSION"))
             .build();
 
-        let inputs = [
-            gpiob.pb1.into_pull_up_input().erase(),
-            gpiob.pb0.into_pull_up_input().erase(),
-        ];
-        let outputs = [
-            gpioa.pa7.into_push_pull_output().erase(),
-            gpioa.pa6.into_push_pull_output().erase(),
-        ];
+        let mut matrix = key_matrix!(
+            rows: [gpioa.pa7, gpioa.pa6],
+            cols: [gpiob.pb1, gpiob.pb0],
+        );
+        // A longer scan would starve the tasks below its priority
+        debug_assert!(matrix.max_scan_us() < SCAN_PERIOD_US);
 
-        let local_grid = LocalGrid::new(inputs, outputs, PinState::Low);
+        // PA6, PA7, PB0 and PB1 have their own EXTI lines
+        let wake_lines =
+            matrix::exti_lines(matrix.inputs_mut().iter().map(|pin| pin.pin_id())).unwrap();
+        let mut syscfg = c.device.SYSCFG.constrain();
+        let mut exti = c.device.EXTI;
+        // Pressed keys bring their input to the active level
+        let edge = match MATRIX.active_level() {
+            PinState::Low => Edge::Falling,
+            PinState::High => Edge::Rising,
+        };
+        for pin in matrix.inputs_mut() {
+            pin.make_interrupt_source(&mut syscfg);
+            pin.trigger_on_edge(&mut exti, edge);
+        }
 
         let status_grid = GridState::new([
             [
                 Button::new(ButtonAction::Simple(KeyboardCode::A)),
-                Button::new(ButtonAction::Simple(KeyboardCode::B)),
+                Button::new(ButtonAction::Simple(KeyboardCode::C)),
             ],
             [
-                Button::new(ButtonAction::Simple(KeyboardCode::C)),
+                Button::new(ButtonAction::Simple(KeyboardCode::B)),
                 Button::new(ButtonAction::Simple(KeyboardCode::D)),
             ],
         ]);
 
+        // The monotonic starts counting from zero when init returns
+        scan::spawn(Instant::from_ticks(0)).ok();
+
         info!("Init completed");
 
         (
             Shared {
                 usb_dev,
                 usb_class,
+                // usbd-hid acknowledges SET_IDLE without exposing the rate, so keep the HID
+                // default of an infinite idle period
+                reports: ReportQueue::new(None),
                 status_grid,
             },
-            Local { local_grid, timer },
-            init::Monotonics(),
+            Local { matrix, wake_lines },
+            init::Monotonics(mono),
         )
     }
 
@@ -136,36 +248,71 @@ mod app {
         defmt::info!("idle");
 
         loop {
-            continue;
+            cortex_m::asm::wfi();
         }
     }
 
-    #[task(binds = TIM3, priority = 1, shared = [ status_grid, usb_class ], local = [ local_grid, timer ])]
-    fn local_tick(c: local_tick::Context) {
-        c.local.timer.wait().ok();
-
-        for event in c.local.local_grid.get_events() {
-            handle_event::spawn(event).ok();
+    #[task(
+        priority = 1,
+        local = [matrix, wake_lines, idle: IdleTimer = IdleTimer::new(MATRIX_IDLE_MS)]
+    )]
+    fn scan(c: scan::Context, at: Instant) {
+        for event in c.local.matrix.scan(&mut SpinDelay) {
+            if let Some(event) = HALF_MAP.event(event) {
+                handle_event::spawn(event, at).ok();
+            }
         }
 
         keyboard_tick::spawn().ok();
+
+        let busy = c.local.matrix.any_pressed();
+        if c.local.idle.update(millis(at), busy) {
+            // Enabled first so that a key pressed meanwhile wakes the scan right away
+            enable_wake(*c.local.wake_lines);
+            c.local.matrix.sleep();
+            return;
+        }
+
+        // Scheduled from the previous deadline to avoid drifting
+        let next = at + Duration::micros(SCAN_PERIOD_US.into());
+        scan::spawn_at(next, next).ok();
     }
 
-    #[task(priority = 3, capacity = 8, shared = [ status_grid ])]
-    fn handle_event(mut c: handle_event::Context, event: ButtonStatusEvent) {
-        info!("Event: {:?}", event);
-        c.shared.status_grid.lock(|status_grid| {
-            status_grid.set_pressed(event.inp, event.out, event.pressed);
-        })
+    // EXTI lines of the matrix inputs
+    #[task(binds = EXTI0, priority = 1)]
+    fn wake_exti0(_: wake_exti0::Context) {
+        resume_scan();
     }
 
-    #[task(priority = 3, shared = [ usb_class, status_grid ])]
-    fn keyboard_tick(c: keyboard_tick::Context) {
-        (c.shared.usb_class, c.shared.status_grid).lock(|usb_class, status_grid| {
+    #[task(binds = EXTI1, priority = 1)]
+    fn wake_exti1(_: wake_exti1::Context) {
+        resume_scan();
+    }
+
+    #[task(binds = EXTI9_5, priority = 1)]
+    fn wake_exti9_5(_: wake_exti9_5::Context) {
+        resume_scan();
+    }
+
+    #[task(priority = 3, capacity = 8, shared = [ status_grid, reports ])]
+    fn handle_event(c: handle_event::Context, event: KeyEvent, at: Instant) {
+        info!("Event: {:?} at {} ms", event, millis(at));
+        (c.shared.status_grid, c.shared.reports).lock(|status_grid, reports| {
+            let position = event.position;
+            status_grid.set_pressed(position.row.index(), position.col.index(), event.pressed);
             let report: KeyboardReport = status_grid
                 .to_report::<KeyboardReport, LedStatus, Infallible>()
                 .unwrap();
-            while let Ok(0) = usb_class.push_input(&report) {}
+            reports.push(report);
+        })
+    }
+
+    #[task(priority = 3, shared = [ usb_class, reports ])]
+    fn keyboard_tick(c: keyboard_tick::Context) {
+        let now_ms = millis(monotonics::now());
+
+        (c.shared.usb_class, c.shared.reports).lock(|usb_class, reports| {
+            reports.send(now_ms, |report| usb_class.push_input(report));
         })
     }
 
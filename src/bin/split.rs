@@ -3,42 +3,541 @@
 
 use lets_split as _; // global logger + panicking-behavior + memory layout
 
-#[rtic::app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [ TIM4 ])]
+#[cfg(all(feature = "half-duplex", feature = "i2c-link"))]
+compile_error!(
+    "The `half-duplex` and `i2c-link` features select different links between the halves"
+);
+
+/// Builds the `KeyMatrix` of the given row and column pins, the inputs and outputs depending
+/// on the `MATRIX` configuration
+macro_rules! key_matrix {
+    (rows: [$($row:expr),* $(,)?], cols: [$($col:expr),* $(,)?] $(,)?) => {{
+        let pull = match MATRIX.pull {
+            Pull::Up => gpio::Pull::Up,
+            Pull::Down => gpio::Pull::Down,
+        };
+        if MATRIX.rows_driven() {
+            KeyMatrix::new(
+                MATRIX,
+                [$($col.into_input().internal_resistor(pull).erase()),*],
+                [$($row.into_push_pull_output().erase()),*],
+            )
+        } else {
+            KeyMatrix::new(
+                MATRIX,
+                [$($row.into_input().internal_resistor(pull).erase()),*],
+                [$($col.into_push_pull_output().erase()),*],
+            )
+        }
+    }};
+}
+
+#[rtic::app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [ TIM3, TIM4, TIM5 ])]
 mod app {
-    use core::convert::{From, Infallible, TryFrom};
+    use core::convert::{From, Infallible};
     use defmt::println;
+    use dwt_systick_monotonic::DwtSystick;
+    use embedded_hal::blocking::delay::DelayUs;
+    use heapless::Deque;
     use keyboard_io::{
-        buttons::{
-            shortcuts::{bs, row},
-            ButtonAction, ButtonStatusEvent, GridState, LocalGrid, StatefulInputPin,
-        },
+        buttons::{GridState, StatefulInputPin},
         codes::KeyboardCode,
         hid::keyboard::{KeyboardReport, LedStatus},
         prelude::{HIDClass, PinState, SerializedDescriptor, UsbDeviceBuilder, UsbVidPid},
     };
+    #[cfg(feature = "i2c-link")]
+    use lets_split::i2c::{self, I2cBus, I2cLink, I2cMaster, I2cSlave};
+    #[cfg(feature = "joystick")]
+    use lets_split::joystick::{Joystick, JoystickConfig, Motion, MOUSE_DESCRIPTOR};
+    #[cfg(not(feature = "i2c-link"))]
+    use lets_split::{
+        arbiter::{Arbiter, ArbiterConfig},
+        dma::TxBuffer,
+        link::{LinkStats, UartError},
+        transport::UartTransport,
+    };
+    use lets_split::{
+        bootloader,
+        bounce::{BounceConfig, BounceStats},
+        clock::ClockOffset,
+        diagnostic::{DiagnosticConfig, Diagnostics, Finding},
+        encoder::{EncoderEvent, EncoderMap, Quadrature},
+        gesture::{Gesture, GestureConfig, GestureDetector},
+        health::{HealthConfig, KeySet, LinkMonitor, LinkState},
+        heatmap::{self, Heatmap},
+        keymap::{Action, Keymap, LayerState},
+        layout,
+        led::{Indicators, LedConfig},
+        link::Message,
+        matrix::{self, DiodeDirection, IdleTimer, Matrix, MatrixConfig, Pull},
+        merge::EventMerger,
+        position::{HalfMap, KeyEvent, KeyPosition},
+        protocol::{self, Effect, RAW_HID_DESCRIPTOR, REPORT_SIZE},
+        report::ReportQueue,
+        settings::Settings,
+        side::Side,
+        storage::Store,
+        transport::Transport,
+    };
+    #[cfg(feature = "joystick")]
+    use stm32f4xx_hal::{
+        adc::{
+            config::{AdcConfig, SampleTime},
+            Adc,
+        },
+        pac::ADC1,
+    };
+    #[cfg(not(feature = "i2c-link"))]
+    use stm32f4xx_hal::{
+        dma::{
+            config::DmaConfig, MemoryToPeripheral, PeripheralToMemory, Stream2, Stream7,
+            StreamsTuple, Transfer,
+        },
+        pac::{Interrupt, DMA2, USART1},
+        serial,
+    };
     use stm32f4xx_hal::{
-        gpio::{alt, EPin, Input, Output, PushPull},
-        interrupt,
+        flash::{FlashExt, LockedFlash},
+        gpio::{self, alt, EPin, Edge, ExtiPin, Input, Output, PinExt, PushPull},
         otg_fs::{UsbBusType, USB},
-        pac::{self, USART1},
+        pac::EXTI,
         prelude::*,
-        serial, timer,
     };
+    #[cfg(feature = "i2c-link")]
+    use stm32f4xx_hal::{i2c::I2c, pac::I2C2};
     use usb_device::{
         class_prelude::*,
+        device::UsbDeviceState,
         test_class::{PID, VID},
     };
 
     type UsbKeyboardClass = HIDClass<'static, UsbBusType>;
+    type UsbRawClass = HIDClass<'static, UsbBusType>;
+    type UsbMouseClass = HIDClass<'static, UsbBusType>;
     type UsbDevice = keyboard_io::prelude::UsbDevice<'static, UsbBusType>;
     // type DebouncedInputPin = DebouncedPin<EPin<Input<PullUp>>>;
     type InputPin = EPin<Input>;
     type OutputPin = EPin<Output<PushPull>>;
+    type KeyMatrix = Matrix<InputPin, OutputPin, 4, 6>;
+    /// X and Y axes of the joystick of the right half
+    #[cfg(feature = "joystick")]
+    type JoystickPins = (gpio::PA2<gpio::Analog>, gpio::PA3<gpio::Analog>);
+    type KeyboardSettings = Settings<4, 12, 4>;
+    type KeyboardHeatmap = Heatmap<4, 4, 12>;
+    type StatusGrid = GridState<KeyboardCode, 4, 12, 3>;
+    /// Transport of the link between the halves
+    #[cfg(not(feature = "i2c-link"))]
+    type Link = UartTransport<LINK_TX_QUEUE_SIZE>;
+    #[cfg(feature = "i2c-link")]
+    type Link = I2cLink<4, 12>;
+    #[cfg(not(feature = "i2c-link"))]
+    type RxTransfer = Transfer<
+        Stream2<DMA2>,
+        4,
+        serial::Rx<USART1>,
+        PeripheralToMemory,
+        &'static mut [u8; LINK_RX_BUFFER_SIZE],
+    >;
+    #[cfg(not(feature = "i2c-link"))]
+    type TxTransfer = Transfer<
+        Stream7<DMA2>,
+        4,
+        serial::Tx<USART1>,
+        MemoryToPeripheral,
+        TxBuffer<LINK_TX_BUFFER_SIZE>,
+    >;
+
+    /// Core clock, also the frequency of the monotonic timer
+    const SYSCLK_HZ: u32 = 84_000_000;
+
+    #[monotonic(binds = SysTick, default = true)]
+    type Mono = DwtSystick<SYSCLK_HZ>;
+    type Instant = <Mono as rtic::Monotonic>::Instant;
+    type Duration = <Mono as rtic::Monotonic>::Duration;
+
+    /// Busy wait counted in core cycles, for the settle times of the matrix
+    struct SpinDelay;
+
+    impl DelayUs<u32> for SpinDelay {
+        fn delay_us(&mut self, us: u32) {
+            cortex_m::asm::delay(us * (SYSCLK_HZ / 1_000_000));
+        }
+    }
+
+    /// Milliseconds since boot at `instant`, wrapping around
+    fn millis(instant: Instant) -> u32 {
+        instant.duration_since_epoch().to_millis() as u32
+    }
+
+    /// Microseconds since boot at `instant`, wrapping around
+    fn micros(instant: Instant) -> u32 {
+        instant.duration_since_epoch().to_micros() as u32
+    }
+
+    /// Connects the receiver of USART1 to its TX pin
+    #[cfg(feature = "half-duplex")]
+    fn enable_half_duplex() {
+        // HDSEL can be changed only while the USART is disabled
+        let usart = unsafe { &*USART1::ptr() };
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        usart.cr3.modify(|_, w| w.hdsel().set_bit());
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+    }
+
+    /// Turns I2C2, set up by the HAL as a master, into a slave answering at `address`, its bus
+    /// events are handled by [`i2c_event`]
+    #[cfg(feature = "i2c-link")]
+    fn enable_i2c_slave(address: u8) {
+        let i2c = unsafe { &*I2C2::ptr() };
+        // Bit 14 must be kept set
+        i2c.oar1
+            .write(|w| unsafe { w.bits(u32::from(address) << 1 | 1 << 14) });
+        i2c.cr1.modify(|_, w| w.ack().set_bit());
+        i2c.cr2.modify(|_, w| {
+            w.itevten()
+                .set_bit()
+                .itbufen()
+                .set_bit()
+                .iterren()
+                .set_bit()
+        });
+    }
+
+    /// Lets the link send the queued frames, the I²C link sends them with its next poll
+    fn flush_link() {
+        #[cfg(not(feature = "i2c-link"))]
+        rtic::pend(Interrupt::DMA2_STREAM7);
+    }
+
+    /// Lets the given EXTI lines wake the matrix
+    fn enable_wake(lines: u32) {
+        let exti = unsafe { &*EXTI::ptr() };
+        // Edges seen while scanning are stale
+        exti.pr.write(|w| unsafe { w.bits(lines) });
+        exti.imr.modify(|r, w| unsafe { w.bits(r.bits() | lines) });
+    }
+
+    /// Masks the wake interrupts and resumes the matrix scan if it sleeps
+    fn resume_scan() {
+        // The wake lines are the only EXTI lines in use
+        let exti = unsafe { &*EXTI::ptr() };
+        exti.imr.write(|w| unsafe { w.bits(0) });
+        let pending = exti.pr.read().bits();
+        exti.pr.write(|w| unsafe { w.bits(pending) });
+        // Fails if the scan is already scheduled
+        scan::spawn(monotonics::now()).ok();
+    }
+
+    /// Rebuilds the `status_grid` with `default` as base layer, keeping the keys held down
+    fn rebuild_grid<K: From<KeyboardCode>>(
+        status_grid: &mut GridState<K, 4, 12, 3>,
+        settings: &KeyboardSettings,
+        default: usize,
+        grid_keys: &KeySet<4, 12>,
+    ) {
+        *status_grid = settings.keymap.grid_state_with_default(default);
+        for position in grid_keys.iter() {
+            status_grid.set_pressed(position.row.index(), position.col.index(), true);
+        }
+    }
+
+    /// Applies a key event to the layers and the HID reports, returns the action of the key or
+    /// `None` if the key already was in that state
+    fn apply_key(
+        event: KeyEvent,
+        grid_keys: &mut KeySet<4, 12>,
+        status_grid: &mut StatusGrid,
+        layers: &mut LayerState<4>,
+        settings: &KeyboardSettings,
+        indicators: &mut Indicators,
+        reports: &mut ReportQueue<KeyboardReport, 8>,
+    ) -> Option<Action> {
+        let position = event.position;
+        if !grid_keys.set(position, event.pressed) {
+            return None;
+        }
+        let action = layers.update(&settings.keymap, event);
+        indicators.layer = layers.active() as u8;
+        status_grid.set_pressed(position.row.index(), position.col.index(), event.pressed);
+        let report: KeyboardReport = status_grid
+            .to_report::<KeyboardReport, LedStatus, Infallible>()
+            .unwrap();
+        reports.push(report);
+        Some(action)
+    }
+
+    /// Period of the matrix scan
+    const SCAN_PERIOD_US: u32 = 250;
+
+    /// Gray code steps between two detents of the EC11 encoders
+    const ENCODER_STEPS_PER_DETENT: u8 = 4;
+
+    /// Period of the joystick sampling, also the polling interval of the mouse interface
+    #[cfg(feature = "joystick")]
+    const JOYSTICK_PERIOD_MS: u32 = 10;
+
+    /// Response of the joystick, read by the 12-bit ADC
+    #[cfg(feature = "joystick")]
+    const JOYSTICK: JoystickConfig = JoystickConfig {
+        dead_zone: 120,
+        range: 1_800,
+        max_speed: 20,
+        acceleration: 700,
+        invert_x: false,
+        invert_y: false,
+    };
+
+    /// Wiring of the key matrix of both halves
+    const MATRIX: MatrixConfig = MatrixConfig {
+        diodes: DiodeDirection::Col2Row,
+        pull: Pull::Up,
+        select_delay_us: 1,
+        unselect_delay_us: 30,
+        ghost_filter: false,
+    };
+
+    /// Time without activity after which the matrix stops scanning until a key or the button is
+    /// pressed
+    const MATRIX_IDLE_MS: u32 = 1_000;
+
+    /// Baud rate of the link between the halves, exact with the 84 MHz APB2 clock
+    #[cfg(not(feature = "i2c-link"))]
+    const LINK_BAUD_RATE: u32 = 1_000_000;
+
+    /// Token passing of the single-wire link, the left half is the primary one
+    #[cfg(not(feature = "i2c-link"))]
+    const LINK_ARBITRATION: ArbiterConfig = ArbiterConfig {
+        poll_us: 500,
+        timeout_us: 5_000,
+    };
+
+    /// Size of the DMA buffers of the link, a reception is handled when the line goes idle or
+    /// the buffer is full
+    const LINK_RX_BUFFER_SIZE: usize = 64;
+    const LINK_TX_BUFFER_SIZE: usize = 64;
+
+    /// Size of the ring buffer of the frames waiting for transmission
+    #[cfg(not(feature = "i2c-link"))]
+    const LINK_TX_QUEUE_SIZE: usize = 256;
+
+    /// Period of the link statistics log
+    #[cfg(not(feature = "i2c-link"))]
+    const LINK_STATS_PERIOD_MS: u32 = 10_000;
+
+    /// Clock of the I²C link, the left half is the master
+    #[cfg(feature = "i2c-link")]
+    const LINK_I2C_KHZ: u32 = 400;
+
+    /// Period of the polls of the right half by the left one on the I²C link
+    #[cfg(feature = "i2c-link")]
+    const LINK_POLL_PERIOD_US: u32 = 1_000;
+
+    /// Delay before key events are handled, longer than the transmission of an event frame (12
+    /// bytes, 120 us at 1 Mbaud) and the scan period so that the events of both halves are
+    /// handled in order. On a single wire the frames also wait for the bus token, over I²C for
+    /// the next poll.
+    const MERGE_DELAY_US: u32 = if cfg!(any(feature = "half-duplex", feature = "i2c-link")) {
+        2_000
+    } else {
+        1_000
+    };
+
+    /// Period of the heartbeat frames sent to the other half
+    const HEARTBEAT_PERIOD_MS: u32 = 100;
+
+    /// Silences after which the link is degraded and down, a few heartbeats long
+    const LINK_HEALTH: HealthConfig = HealthConfig {
+        degraded_ms: 300,
+        down_ms: 1_000,
+    };
+
+    /// Period of the clock offset measurements with the other half
+    const CLOCK_SYNC_PERIOD_MS: u32 = 1_000;
+
+    /// Period of the saves of the key press counters, when they changed
+    const HEATMAP_SAVE_PERIOD_MS: u32 = 300_000;
+
+    /// Timing of the gestures of the PA0 button
+    const BUTTON_GESTURES: GestureConfig = GestureConfig {
+        debounce_ms: 20,
+        long_press_ms: 1000,
+        double_press_ms: 300,
+    };
+
+    /// Matrices of the halves on the keyboard, the right half is mirrored and its first column
+    /// is the outer one
+    const LEFT_MAP: HalfMap<4, 6> = HalfMap::offset(0);
+    const RIGHT_MAP: HalfMap<4, 6> = HalfMap::mirrored(6);
+
+    /// Matrix test, started and ended by holding the four corner keys together
+    const DIAGNOSTICS: DiagnosticConfig = DiagnosticConfig {
+        boot_ms: 1_000,
+        chatter_ms: 20,
+        combo: &[
+            KeyPosition::new(0, 0),
+            KeyPosition::new(0, 11),
+            KeyPosition::new(3, 0),
+            KeyPosition::new(3, 11),
+        ],
+    };
+
+    /// Findings of the matrix test kept until the host reads them, later ones are dropped
+    const DIAGNOSTIC_QUEUE_SIZE: usize = 64;
+
+    /// Windows of the bounce statistics, collected once enabled by the host
+    const BOUNCE_STATS: BounceConfig = BounceConfig {
+        burst_us: 20_000,
+        debounce_us: 5_000,
+    };
+
+    /// States shown by the status LED of each half
+    const LEFT_LED: LedConfig = LedConfig::ALL;
+    const RIGHT_LED: LedConfig = LedConfig {
+        usb: false,
+        ..LedConfig::ALL
+    };
+
+    /// Time left to the host to read the answer to the `Bootloader` command before the reset
+    const BOOTLOADER_DELAY_MS: u32 = 50;
+
+    /// Sampling period of the LED patterns
+    const LED_TICK_MS: u32 = 20;
+
+    const fn k(code: KeyboardCode) -> Action {
+        Action::Key(code as u8)
+    }
+
+    const fn mo(layer: u8) -> Action {
+        Action::MomentaryLayer(layer)
+    }
+
+    const __: Action = Action::Transparent;
+    const BOOT: Action = Action::Bootloader;
+    const RESET: Action = Action::Reset;
+
+    /// Compiled-in keymap, restored when the host resets the keymap
+    #[rustfmt::skip]
+    const DEFAULT_KEYMAP: Keymap<4, 12, 4> = {
+        use KeyboardCode::*;
+        Keymap::new([
+            [
+                [
+                    k(Escape), k(Q), k(W), k(E), k(R), k(T),
+                    k(Y), k(U), k(I), k(O), k(P), k(BSpace),
+                ],
+                [
+                    k(Tab), k(A), k(S), k(D), k(F), k(G),
+                    k(H), k(J), k(K), k(L), k(SColon), k(Quote),
+                ],
+                [
+                    k(LShift), k(Z), k(X), k(C), k(V), k(B),
+                    k(N), k(M), k(Comma), k(Dot), k(Slash), k(Enter),
+                ],
+                [
+                    k(LCtrl), mo(3), k(LAlt), k(LGui), mo(1), k(Space),
+                    k(Space), mo(2), k(Left), k(Down), k(Up), k(Right),
+                ],
+            ],
+            [
+                [
+                    k(Grave), k(Kb1), k(Kb2), k(Kb3), k(Kb4), k(Kb5),
+                    k(Kb6), k(Kb7), k(Kb8), k(Kb9), k(Kb0), __,
+                ],
+                [
+                    __, __, __, __, __, __,
+                    __, __, k(LBracket), k(RBracket), k(BSlash), k(NonUsBSlash),
+                ],
+                [
+                    __, __, __, __, __, __,
+                    __, __, __, __, __, __,
+                ],
+                [
+                    __, __, __, __, __, __,
+                    __, __, __, k(VolDown), k(VolUp), k(Mute),
+                ],
+            ],
+            [
+                [
+                    __, __, __, __, __, __,
+                    __, __, __, k(Minus), k(Equal), k(Delete),
+                ],
+                [
+                    k(F1), k(F2), k(F3), k(F4), k(F5), k(F6),
+                    k(F7), k(F8), k(F9), k(F10), k(F11), k(F12),
+                ],
+                [
+                    __, __, __, __, __, __,
+                    __, __, __, k(RCtrl), k(Insert), __,
+                ],
+                [
+                    __, __, __, __, __, __,
+                    __, __, k(Home), k(PgDown), k(PgUp), k(End),
+                ],
+            ],
+            [
+                [
+                    BOOT, RESET, __, __, __, __,
+                    __, __, __, __, __, k(PScreen),
+                ],
+                [
+                    __, k(Left), k(Down), k(Up), k(Right), __,
+                    __, __, __, __, __, __,
+                ],
+                [
+                    __, __, __, __, __, __,
+                    __, __, __, k(Application), __, __,
+                ],
+                [
+                    __, __, __, __, __, __,
+                    __, __, __, __, __, __,
+                ],
+            ],
+        ])
+    };
+
+    /// Actions of the encoders of the left and right halves, clockwise first
+    #[rustfmt::skip]
+    const ENCODER_MAP: EncoderMap<2, 4> = {
+        use KeyboardCode::*;
+        EncoderMap::new([
+            [[k(VolUp), k(VolDown)], [k(PgDown), k(PgUp)]],
+            [[__, __], [k(Right), k(Left)]],
+            [[__, __], [k(Down), k(Up)]],
+            [[__, __], [__, __]],
+        ])
+    };
 
     // Shared resources go here
     #[shared]
     struct Shared {
-        status_grid: GridState<KeyboardCode, 4, 12, 3>,
+        bounce: BounceStats<4, 12>,
+        /// Raw HID reports of the findings of the matrix test, until the host reads them
+        findings: Deque<[u8; REPORT_SIZE], DIAGNOSTIC_QUEUE_SIZE>,
+        flash: LockedFlash,
+        /// Keys pressed in the `status_grid`, pressed again when it is rebuilt
+        grid_keys: KeySet<4, 12>,
+        /// Key press counters, counted on the half connected to the host
+        heatmap: KeyboardHeatmap,
+        indicators: Indicators,
+        layers: LayerState<4>,
+        link: Link,
+        /// Keys pressed on this half, reported again when the link comes back
+        local_keys: KeySet<4, 12>,
+        merger: EventMerger<KeyEvent, 16>,
+        monitor: LinkMonitor,
+        /// Interface of the joystick, only registered with the `joystick` feature
+        mouse_class: Option<UsbMouseClass>,
+        raw_class: UsbRawClass,
+        reports: ReportQueue<KeyboardReport, 8>,
+        /// Keys pressed on the other half, released when the link drops
+        remote_keys: KeySet<4, 12>,
+        #[cfg(not(feature = "i2c-link"))]
+        rx_transfer: RxTransfer,
+        settings: KeyboardSettings,
+        side: Side,
+        status_grid: StatusGrid,
+        /// Keys held since the matrix test swallowed their press, left out of the reconciliation
+        swallowed: KeySet<4, 12>,
         usb_dev: UsbDevice,
         usb_class: UsbKeyboardClass,
     }
@@ -47,21 +546,45 @@ mod app {
     #[local]
     struct Local {
         button: StatefulInputPin<InputPin>,
-        intra_rx: serial::Rx<USART1>,
-        intra_tx: serial::Tx<USART1>,
-        is_left_side: bool,
+        /// A and B outputs of the encoder of this half
+        encoder_pins: (InputPin, InputPin),
+        /// Index of the encoder of this half on the keyboard
+        encoder_index: u8,
+        /// Places the keys of this half on the keyboard
+        half_map: HalfMap<4, 6>,
+        heatmap_store: Store,
+        /// Bus of the I²C link, on the left half
+        #[cfg(feature = "i2c-link")]
+        i2c_bus: Option<I2cBus<I2c<I2C2>>>,
+        /// ADC and axes of the joystick, only fitted to the right half
+        #[cfg(feature = "joystick")]
+        joystick_adc: Option<(Adc<ADC1>, JoystickPins)>,
         led: OutputPin,
-        local_grid: LocalGrid<InputPin, OutputPin, 6, 4>,
-        timer: timer::CounterUs<pac::TIM3>,
+        led_config: LedConfig,
+        matrix: KeyMatrix,
+        /// Buffer swapped with the one of the DMA reception
+        #[cfg(not(feature = "i2c-link"))]
+        rx_spare: Option<&'static mut [u8; LINK_RX_BUFFER_SIZE]>,
+        store: Store,
+        /// EXTI lines of the matrix inputs, the button and the encoder, 0 if the matrix never
+        /// sleeps
+        wake_lines: u32,
+        /// Buffer filled while the DMA sends the other one
+        #[cfg(not(feature = "i2c-link"))]
+        tx_spare: Option<TxBuffer<LINK_TX_BUFFER_SIZE>>,
+        #[cfg(not(feature = "i2c-link"))]
+        tx_transfer: TxTransfer,
     }
 
     #[init(local = [
       usb_allocator: Option<UsbBusAllocator<UsbBusType>> = None,
       ep_memory: [u32; 1024] = [0; 1024],
+      rx_buffer_a: [u8; LINK_RX_BUFFER_SIZE] = [0; LINK_RX_BUFFER_SIZE],
+      rx_buffer_b: [u8; LINK_RX_BUFFER_SIZE] = [0; LINK_RX_BUFFER_SIZE],
+      tx_buffer_a: [u8; LINK_TX_BUFFER_SIZE] = [0; LINK_TX_BUFFER_SIZE],
+      tx_buffer_b: [u8; LINK_TX_BUFFER_SIZE] = [0; LINK_TX_BUFFER_SIZE],
     ])]
-    fn init(c: init::Context) -> (Shared, Local, init::Monotonics) {
-        rtic::pend(interrupt::TIM3);
-
+    fn init(mut c: init::Context) -> (Shared, Local, init::Monotonics) {
         let usb_allocator = c.local.usb_allocator;
         let ep_memory = c.local.ep_memory;
 
@@ -73,9 +596,12 @@ mod app {
             .require_pll48clk()
             .freeze();
 
-        let mut timer = c.device.TIM3.counter_us(&clocks);
-        timer.start(250.micros()).unwrap();
-        timer.listen(timer::Event::Update);
+        let mono = DwtSystick::new(
+            &mut c.core.DCB,
+            c.core.DWT,
+            c.core.SYST,
+            clocks.sysclk().raw(),
+        );
 
         let gpioa = c.device.GPIOA.split();
         let gpiob = c.device.GPIOB.split();
@@ -84,6 +610,9 @@ mod app {
         let mut led = gpioc.pc13.into_push_pull_output().erase();
         led.set_low();
 
+        // Keeps the debug probe and RTT working while idle sleeps
+        c.device.DBGMCU.cr.modify(|_, w| w.dbg_sleep().set_bit());
+
         let usb = USB {
             usb_global: c.device.OTG_FS_GLOBAL,
             usb_device: c.device.OTG_FS_DEVICE,
@@ -96,181 +625,281 @@ mod app {
         let usb_allocator = usb_allocator.as_ref().unwrap();
 
         let usb_class = HIDClass::new(usb_allocator, KeyboardReport::desc(), 10);
+        let raw_class = HIDClass::new(usb_allocator, RAW_HID_DESCRIPTOR, 1);
+        #[cfg(feature = "joystick")]
+        let mouse_class = Some(HIDClass::new(
+            usb_allocator,
+            MOUSE_DESCRIPTOR,
+            JOYSTICK_PERIOD_MS as u8,
+        ));
+        #[cfg(not(feature = "joystick"))]
+        let mouse_class = None;
         let usb_dev: UsbDevice = UsbDeviceBuilder::new(usb_allocator, UsbVidPid(VID, PID))
             .manufacturer("Bertof - RIIR Task Force")
             .product("Let's Split I")
             .serial_number(env!("CARGO_PKG_VERSION"))
             .build();
 
-        let serial = serial::Serial::new(
-            c.device.USART1,
-            (gpioa.pa9.into_alternate(), gpioa.pa10.into_alternate()),
-            serial::config::Config::default().baudrate(38_400.bps()),
-            &clocks,
-        )
-        .unwrap();
-        let (intra_tx, mut intra_rx) = serial.split();
-        intra_rx.listen();
+        // Serial link, replaced by I2C2 with the `i2c-link` feature
+        #[cfg(not(feature = "i2c-link"))]
+        let (rx_transfer, tx_transfer) = {
+            #[cfg(not(feature = "half-duplex"))]
+            let link_pins = (gpioa.pa9.into_alternate(), gpioa.pa10.into_alternate());
+            // Single wire on PA9, released by both halves when idle
+            #[cfg(feature = "half-duplex")]
+            let link_pins = (
+                gpioa.pa9.into_alternate_open_drain().internal_pull_up(true),
+                stm32f4xx_hal::gpio::NoPin::new(),
+            );
+            let serial = serial::Serial::new(
+                c.device.USART1,
+                link_pins,
+                serial::config::Config::default()
+                    .baudrate(LINK_BAUD_RATE.bps())
+                    .dma(serial::config::DmaConfig::TxRx),
+                &clocks,
+            )
+            .unwrap();
+            #[cfg(feature = "half-duplex")]
+            enable_half_duplex();
+            let (intra_tx, mut intra_rx) = serial.split();
+            intra_rx.listen_idle();
+
+            let streams = StreamsTuple::new(c.device.DMA2);
+            let dma_config = DmaConfig::default()
+                .memory_increment(true)
+                .transfer_complete_interrupt(true);
+            let mut rx_transfer = Transfer::init_peripheral_to_memory(
+                streams.2,
+                intra_rx,
+                c.local.rx_buffer_a,
+                None,
+                dma_config,
+            );
+            rx_transfer.start(|_rx| {});
+            // Started by the first frame to send
+            let tx_transfer = Transfer::init_memory_to_peripheral(
+                streams.7,
+                intra_tx,
+                TxBuffer::new(c.local.tx_buffer_a),
+                None,
+                dma_config,
+            );
+            (rx_transfer, tx_transfer)
+        };
 
-        let button = StatefulInputPin::new(gpioa.pa0.into_pull_up_input().erase());
+        let mut button_pin = gpioa.pa0.into_pull_up_input().erase();
+        // The common pin of the encoder is grounded
+        let mut encoder_pins = (
+            gpiob.pb12.into_pull_up_input().erase(),
+            gpiob.pb13.into_pull_up_input().erase(),
+        );
 
-        // Jumper to ground on right side of the keyboard
+        let mut flash = LockedFlash::new(c.device.FLASH);
+        let partition = layout::settings();
+        let mut store = Store::new(partition.offset, partition.size);
+        let mut buf = [0; KeyboardSettings::SIZE];
+        let settings = match store.load(&mut flash, &mut buf) {
+            Ok(Some(len)) => KeyboardSettings::decode(&buf[..len]),
+            _ => None,
+        }
+        .unwrap_or_else(|| {
+            println!("No stored settings, using the compiled-in keymap");
+            KeyboardSettings::new(DEFAULT_KEYMAP)
+        });
+
+        let partition = layout::heatmap();
+        let mut heatmap_store = Store::new(partition.offset, partition.size);
+        let mut buf = [0; KeyboardHeatmap::SIZE];
+        let heatmap = match heatmap_store.load(&mut flash, &mut buf) {
+            Ok(Some(len)) => KeyboardHeatmap::decode(&buf[..len]),
+            _ => None,
+        }
+        .unwrap_or_default();
+
+        // Jumper to ground on right side of the keyboard, overrides the stored side
         let side_jumper_input = gpioa.pa1.into_pull_up_input().erase();
         let mut side_jumper_output = gpioa.pa15.into_push_pull_output().erase();
         side_jumper_output.set_low();
-        let is_left_side = side_jumper_input.is_high();
+        let side = Side::resolve(side_jumper_input.is_low(), settings.side);
         side_jumper_output.set_high();
 
-        println!("Left side: {}", is_left_side);
+        println!("Side: {} (stored: {})", side, settings.side);
 
-        let (inputs, outputs) = if is_left_side {
-            (
-                [
-                    gpioa.pa4.into_pull_up_input().erase(),
-                    gpioa.pa3.into_pull_up_input().erase(),
-                    gpioa.pa2.into_pull_up_input().erase(),
-                    gpiob.pb9.into_pull_up_input().erase(),
-                    gpiob.pb8.into_pull_up_input().erase(),
-                    gpiob.pb7.into_pull_up_input().erase(),
-                ],
-                [
-                    gpiob.pb3.into_push_pull_output().erase(),
-                    gpiob.pb4.into_push_pull_output().erase(),
-                    gpiob.pb5.into_push_pull_output().erase(),
-                    gpiob.pb6.into_push_pull_output().erase(),
-                ],
-            )
+        #[cfg(not(feature = "i2c-link"))]
+        let link = UartTransport::new(if cfg!(feature = "half-duplex") {
+            Arbiter::half_duplex(side == Side::Left, LINK_ARBITRATION)
         } else {
-            (
-                [
-                    gpioa.pa6.into_pull_up_input().erase(),
-                    gpioa.pa5.into_pull_up_input().erase(),
-                    gpioa.pa4.into_pull_up_input().erase(),
-                    gpiob.pb5.into_pull_up_input().erase(),
-                    gpiob.pb4.into_pull_up_input().erase(),
-                    gpiob.pb3.into_pull_up_input().erase(),
-                ],
-                [
-                    gpiob.pb2.into_push_pull_output().erase(),
-                    gpiob.pb1.into_push_pull_output().erase(),
-                    gpiob.pb0.into_push_pull_output().erase(),
-                    gpioa.pa7.into_push_pull_output().erase(),
-                ],
-            )
+            Arbiter::full_duplex()
+        });
+        // SCL on PB10 and SDA on PB3, the matrix line of PB3 moves to PB14
+        #[cfg(feature = "i2c-link")]
+        let (link, i2c_bus, pb3_line) = {
+            let i2c = I2c::new(
+                c.device.I2C2,
+                (gpiob.pb10, gpiob.pb3),
+                LINK_I2C_KHZ.kHz(),
+                &clocks,
+            );
+            let (link, i2c_bus) = if side == Side::Left {
+                let bus = I2cBus::new(i2c, i2c::DEFAULT_ADDRESS);
+                (I2cLink::Master(I2cMaster::new()), Some(bus))
+            } else {
+                enable_i2c_slave(i2c::DEFAULT_ADDRESS);
+                (I2cLink::Slave(I2cSlave::new()), None)
+            };
+            (link, i2c_bus, gpiob.pb14)
         };
+        #[cfg(not(feature = "i2c-link"))]
+        let pb3_line = gpiob.pb3;
 
-        let local_grid = LocalGrid::new(inputs, outputs, PinState::Low);
-
-        let status_grid = {
-            GridState::new([
-                row([
-                    bs(KeyboardCode::Escape).add_layer(KeyboardCode::Grave, 0),
-                    bs(KeyboardCode::Q).add_layer(KeyboardCode::Kb1, 0),
-                    bs(KeyboardCode::W).add_layer(KeyboardCode::Kb2, 0),
-                    bs(KeyboardCode::E).add_layer(KeyboardCode::Kb3, 0),
-                    bs(KeyboardCode::R).add_layer(KeyboardCode::Kb4, 0),
-                    bs(KeyboardCode::T).add_layer(KeyboardCode::Kb5, 0),
-                    bs(KeyboardCode::Y).add_layer(KeyboardCode::Kb6, 0),
-                    bs(KeyboardCode::U).add_layer(KeyboardCode::Kb7, 0),
-                    bs(KeyboardCode::I).add_layer(KeyboardCode::Kb8, 0),
-                    bs(KeyboardCode::O)
-                        .add_layer(KeyboardCode::Kb9, 0)
-                        .add_layer(KeyboardCode::Minus, 1),
-                    bs(KeyboardCode::P)
-                        .add_layer(KeyboardCode::Kb0, 0)
-                        .add_layer(KeyboardCode::Equal, 1),
-                    bs(KeyboardCode::BSpace)
-                        .add_layer(KeyboardCode::Delete, 1)
-                        .add_layer(KeyboardCode::PScreen, 2),
-                ]),
-                row([
-                    bs(KeyboardCode::Tab).add_layer(KeyboardCode::F1, 1),
-                    bs(KeyboardCode::A)
-                        .add_layer(KeyboardCode::F2, 1)
-                        .add_layer(KeyboardCode::Left, 2),
-                    bs(KeyboardCode::S)
-                        .add_layer(KeyboardCode::F3, 1)
-                        .add_layer(KeyboardCode::Down, 2),
-                    bs(KeyboardCode::D)
-                        .add_layer(KeyboardCode::F4, 1)
-                        .add_layer(KeyboardCode::Up, 2),
-                    bs(KeyboardCode::F)
-                        .add_layer(KeyboardCode::F5, 1)
-                        .add_layer(KeyboardCode::Right, 2),
-                    bs(KeyboardCode::G).add_layer(KeyboardCode::F6, 1),
-                    bs(KeyboardCode::H).add_layer(KeyboardCode::F7, 1),
-                    bs(KeyboardCode::J).add_layer(KeyboardCode::F8, 1),
-                    bs(KeyboardCode::K)
-                        .add_layer(KeyboardCode::LBracket, 0)
-                        .add_layer(KeyboardCode::F9, 1),
-                    bs(KeyboardCode::L)
-                        .add_layer(KeyboardCode::RBracket, 0)
-                        .add_layer(KeyboardCode::F10, 1),
-                    bs(KeyboardCode::SColon)
-                        .add_layer(KeyboardCode::BSlash, 0)
-                        .add_layer(KeyboardCode::F11, 1),
-                    bs(KeyboardCode::Quote)
-                        .add_layer(KeyboardCode::NonUsBSlash, 0)
-                        .add_layer(KeyboardCode::F12, 1),
-                ]),
-                row([
-                    bs(KeyboardCode::LShift),
-                    bs(KeyboardCode::Z),
-                    bs(KeyboardCode::X),
-                    bs(KeyboardCode::C),
-                    bs(KeyboardCode::V),
-                    bs(KeyboardCode::B),
-                    bs(KeyboardCode::N),
-                    bs(KeyboardCode::M),
-                    bs(KeyboardCode::Comma),
-                    bs(KeyboardCode::Dot)
-                        .add_layer(KeyboardCode::RCtrl, 1)
-                        .add_layer(KeyboardCode::Application, 2),
-                    bs(KeyboardCode::Slash).add_layer(KeyboardCode::Insert, 1),
-                    bs(KeyboardCode::Enter),
-                ]),
-                row([
-                    bs(KeyboardCode::LCtrl),
-                    ButtonAction::MomentaryLayer(2),
-                    bs(KeyboardCode::LAlt),
-                    bs(KeyboardCode::LGui),
-                    ButtonAction::MomentaryLayer(0),
-                    bs(KeyboardCode::Space),
-                    bs(KeyboardCode::Space),
-                    ButtonAction::MomentaryLayer(1),
-                    bs(KeyboardCode::Left).add_layer(KeyboardCode::Home, 1),
-                    bs(KeyboardCode::Down)
-                        .add_layer(KeyboardCode::VolDown, 0)
-                        .add_layer(KeyboardCode::PgDown, 1),
-                    bs(KeyboardCode::Up)
-                        .add_layer(KeyboardCode::VolUp, 0)
-                        .add_layer(KeyboardCode::PgUp, 1),
-                    bs(KeyboardCode::Right)
-                        .add_layer(KeyboardCode::Mute, 0)
-                        .add_layer(KeyboardCode::End, 1),
-                ]),
-            ])
+        #[cfg(feature = "joystick")]
+        let mut joystick_pins = None;
+        let (mut matrix, half_map) = if side == Side::Left {
+            let matrix = key_matrix!(
+                rows: [pb3_line, gpiob.pb4, gpiob.pb5, gpiob.pb6],
+                cols: [gpioa.pa4, gpioa.pa3, gpioa.pa2, gpiob.pb9, gpiob.pb8, gpiob.pb7],
+            );
+            (matrix, LEFT_MAP)
+        } else {
+            let matrix = key_matrix!(
+                rows: [gpiob.pb2, gpiob.pb1, gpiob.pb0, gpioa.pa7],
+                cols: [pb3_line, gpiob.pb4, gpiob.pb5, gpioa.pa4, gpioa.pa5, gpioa.pa6],
+            );
+            #[cfg(feature = "joystick")]
+            {
+                joystick_pins = Some((gpioa.pa2.into_analog(), gpioa.pa3.into_analog()));
+            }
+            (matrix, RIGHT_MAP)
+        };
+        // A longer scan would starve the tasks below its priority
+        debug_assert!(matrix.max_scan_us() < SCAN_PERIOD_US);
+        #[cfg(feature = "joystick")]
+        let joystick_adc = {
+            let adc = c.device.ADC1;
+            joystick_pins.map(|pins| (Adc::adc1(adc, true, AdcConfig::default()), pins))
+        };
+
+        let wake_lines = matrix::exti_lines(
+            matrix
+                .inputs_mut()
+                .iter()
+                .chain(Some(&button_pin))
+                .chain([&encoder_pins.0, &encoder_pins.1])
+                .map(|pin| pin.pin_id()),
+        );
+        let wake_lines = match wake_lines {
+            Some(lines) => {
+                let mut syscfg = c.device.SYSCFG.constrain();
+                let mut exti = c.device.EXTI;
+                // Pressed keys bring their input to the active level
+                let edge = match MATRIX.active_level() {
+                    PinState::Low => Edge::Falling,
+                    PinState::High => Edge::Rising,
+                };
+                for pin in matrix.inputs_mut() {
+                    pin.make_interrupt_source(&mut syscfg);
+                    pin.trigger_on_edge(&mut exti, edge);
+                }
+                // The button pulls PA0 to ground
+                button_pin.make_interrupt_source(&mut syscfg);
+                button_pin.trigger_on_edge(&mut exti, Edge::Falling);
+                // Both outputs of the encoder change on each step
+                for pin in [&mut encoder_pins.0, &mut encoder_pins.1] {
+                    pin.make_interrupt_source(&mut syscfg);
+                    pin.trigger_on_edge(&mut exti, Edge::RisingFalling);
+                }
+                lines
+            }
+            None => {
+                println!("The matrix keeps scanning when idle");
+                0
+            }
         };
 
+        let button = StatefulInputPin::new(button_pin);
+
+        let status_grid = settings.keymap.grid_state();
+
+        send_message::spawn(Message::Hello { side }).ok();
+        led_tick::spawn().ok();
+        sync_clock::spawn().ok();
+        heartbeat::spawn().ok();
+        #[cfg(not(feature = "i2c-link"))]
+        log_link::spawn().ok();
+        #[cfg(feature = "half-duplex")]
+        pass_token::spawn().ok();
+        #[cfg(feature = "i2c-link")]
+        if side == Side::Left {
+            poll_link::spawn().ok();
+        }
+        #[cfg(feature = "joystick")]
+        if joystick_adc.is_some() {
+            sample_joystick::spawn().ok();
+        }
+        save_heatmap::spawn_after(Duration::millis(HEATMAP_SAVE_PERIOD_MS.into()), true).ok();
+        // The monotonic starts counting from zero when init returns
+        scan::spawn(Instant::from_ticks(0)).ok();
+
         println!("Init completed");
-        led.set_high();
 
         (
             Shared {
+                bounce: BounceStats::new(BOUNCE_STATS),
+                findings: Deque::new(),
+                flash,
+                grid_keys: KeySet::new(),
+                heatmap,
+                indicators: Indicators::default(),
+                layers: LayerState::new(),
+                link,
+                local_keys: KeySet::new(),
+                merger: EventMerger::new(MERGE_DELAY_US),
+                monitor: LinkMonitor::new(LINK_HEALTH),
+                mouse_class,
+                raw_class,
+                remote_keys: KeySet::new(),
                 usb_dev,
                 usb_class,
+                // usbd-hid acknowledges SET_IDLE without exposing the rate, so keep the HID
+                // default of an infinite idle period
+                reports: ReportQueue::new(None),
+                #[cfg(not(feature = "i2c-link"))]
+                rx_transfer,
+                settings,
+                side,
                 status_grid,
+                swallowed: KeySet::new(),
             },
             Local {
                 button,
-                intra_rx,
-                intra_tx,
-                is_left_side,
+                encoder_pins,
+                encoder_index: match side {
+                    Side::Left => 0,
+                    Side::Right => 1,
+                },
+                half_map,
+                heatmap_store,
+                #[cfg(feature = "i2c-link")]
+                i2c_bus,
+                #[cfg(feature = "joystick")]
+                joystick_adc,
                 led,
-                local_grid,
-                timer,
+                led_config: match side {
+                    Side::Left => LEFT_LED,
+                    Side::Right => RIGHT_LED,
+                },
+                matrix,
+                #[cfg(not(feature = "i2c-link"))]
+                rx_spare: Some(c.local.rx_buffer_b),
+                store,
+                wake_lines,
+                #[cfg(not(feature = "i2c-link"))]
+                tx_spare: Some(TxBuffer::new(c.local.tx_buffer_b)),
+                #[cfg(not(feature = "i2c-link"))]
+                tx_transfer,
             },
-            init::Monotonics(),
+            init::Monotonics(mono),
         )
     }
 
@@ -282,81 +911,966 @@ mod app {
         println!("idle");
 
         loop {
-            continue;
+            cortex_m::asm::wfi();
         }
     }
 
-    #[task(binds = TIM3, priority = 4, local = [local_grid, timer, button, led, is_left_side])]
-    fn local_tick(c: local_tick::Context) {
-        c.local.timer.wait().ok();
+    #[task(
+        priority = 4,
+        shared = [local_keys, merger, reports],
+        local = [
+            matrix,
+            button,
+            encoder_pins,
+            encoder_index,
+            half_map,
+            wake_lines,
+            encoder: Quadrature = Quadrature::new(ENCODER_STEPS_PER_DETENT),
+            gestures: GestureDetector = GestureDetector::new(BUTTON_GESTURES),
+            idle: IdleTimer = IdleTimer::new(MATRIX_IDLE_MS),
+        ]
+    )]
+    fn scan(mut c: scan::Context, at: Instant) {
+        let now_ms = millis(at);
+        let now_us = micros(at);
 
-        for mut event in c.local.local_grid.get_events() {
-            // Handle shift in coordinates
-            if !*c.local.is_left_side {
-                event.inp += 6;
-            };
+        // The button pulls PA0 to ground when pressed
+        let edge = c.local.button.update().map(|state| state == PinState::Low);
+        if let Some(gesture) = c.local.gestures.update(now_ms, edge) {
+            handle_gesture::spawn(gesture).ok();
+        }
 
-            handle_event::spawn(event.clone()).ok();
-            send_event::spawn(event).ok();
+        let (a, b) = &*c.local.encoder_pins;
+        if let Some(direction) = c.local.encoder.update(a.is_high(), b.is_high()) {
+            let index = *c.local.encoder_index;
+            send_message::spawn(Message::Encoder { index, direction }).ok();
+            handle_encoder::spawn(EncoderEvent { index, direction }).ok();
         }
 
+        let half_map = &*c.local.half_map;
+        let events = c.local.matrix.scan(&mut SpinDelay);
+        let merging = (c.shared.local_keys, c.shared.merger).lock(|local_keys, merger| {
+            // Matrix positions without switch are skipped
+            for event in events.into_iter().filter_map(|event| half_map.event(event)) {
+                local_keys.set(event.position, event.pressed);
+
+                send_message::spawn(Message::Event {
+                    position: event.position,
+                    pressed: event.pressed,
+                    time_us: now_us,
+                })
+                .ok();
+                if let Err(event) = merger.push(now_us, event) {
+                    handle_event::spawn(event, now_us).ok();
+                }
+            }
+
+            while let Some((time_us, event)) = merger.pop_ready(now_us) {
+                handle_event::spawn(event, time_us).ok();
+            }
+            !merger.is_empty()
+        });
+
         keyboard_tick::spawn().ok();
+        // Lets the link pass the bus token and notice when it is lost
+        flush_link();
+
+        // Reports left for the host, such as releases of the other half, are still sent
+        let sending = c.shared.reports.lock(|reports| !reports.is_empty());
+        let busy =
+            merging || sending || c.local.matrix.any_pressed() || !c.local.gestures.is_idle();
+        if *c.local.wake_lines != 0 && c.local.idle.update(now_ms, busy) {
+            defmt::debug!("Matrix idle, waiting for a key press");
+            // Enabled first so that a key pressed meanwhile wakes the scan right away
+            enable_wake(*c.local.wake_lines);
+            c.local.matrix.sleep();
+            return;
+        }
+
+        // Scheduled from the previous deadline to avoid drifting
+        let next = at + Duration::micros(SCAN_PERIOD_US.into());
+        scan::spawn_at(next, next).ok();
     }
 
-    #[task(binds = USART1, priority = 5, local = [intra_rx, buf: [u8;4] = [0;4]])]
-    fn rx(c: rx::Context) {
-        let buf = c.local.buf;
+    // EXTI lines of the matrix inputs, the button and the encoder
+    #[task(binds = EXTI0, priority = 4)]
+    fn wake_exti0(_: wake_exti0::Context) {
+        resume_scan();
+    }
 
-        if let Ok(b) = c.local.intra_rx.read() {
-            buf.rotate_left(1);
-            buf[3] = b;
-            // println!("Serial buf: {:?}", buf);
+    #[task(binds = EXTI2, priority = 4)]
+    fn wake_exti2(_: wake_exti2::Context) {
+        resume_scan();
+    }
+
+    #[task(binds = EXTI3, priority = 4)]
+    fn wake_exti3(_: wake_exti3::Context) {
+        resume_scan();
+    }
+
+    #[task(binds = EXTI4, priority = 4)]
+    fn wake_exti4(_: wake_exti4::Context) {
+        resume_scan();
+    }
 
-            if let Ok(event) = ButtonStatusEvent::try_from(&*buf) {
-                handle_event::spawn(event).ok();
+    #[task(binds = EXTI9_5, priority = 4)]
+    fn wake_exti9_5(_: wake_exti9_5::Context) {
+        resume_scan();
+    }
+
+    #[task(binds = EXTI15_10, priority = 4)]
+    fn wake_exti15_10(_: wake_exti15_10::Context) {
+        resume_scan();
+    }
+
+    #[cfg(not(feature = "i2c-link"))]
+    #[task(binds = USART1, priority = 5, shared = [link, rx_transfer], local = [rx_spare])]
+    fn rx(mut c: rx::Context) {
+        let now_us = micros(monotonics::now());
+
+        // Swap the DMA buffer on idle line or when it is full
+        let spare = c.local.rx_spare.take().unwrap();
+        let (buf, len, sr) = c.shared.rx_transfer.lock(|transfer| {
+            // Reading the status register before the data register clears the error flags
+            let sr = unsafe { (*USART1::ptr()).sr.read() };
+            if transfer.is_idle() {
+                transfer.clear_idle_interrupt();
+            }
+            let len = LINK_RX_BUFFER_SIZE - usize::from(transfer.number_of_transfers());
+            let (buf, _) = transfer.next_transfer(spare).unwrap();
+            (buf, len, sr)
+        });
+
+        c.shared.link.lock(|link| {
+            if sr.fe().bit_is_set() {
+                link.uart_error(UartError::Framing);
+            }
+            if sr.nf().bit_is_set() {
+                link.uart_error(UartError::Noise);
+            }
+            if sr.ore().bit_is_set() {
+                link.uart_error(UartError::Overrun);
+            }
+            link.received(&buf[..len], now_us);
+        });
+        *c.local.rx_spare = Some(buf);
+        handle_link::spawn().ok();
+    }
+
+    /// Handles the messages received from the other half, whatever the transport
+    #[task(
+        priority = 4,
+        shared = [indicators, link, local_keys, merger, monitor, remote_keys, side],
+        local = [clock: ClockOffset = ClockOffset::new()]
+    )]
+    fn handle_link(mut c: handle_link::Context) {
+        let now = monotonics::now();
+        let now_ms = millis(now);
+        let now_us = micros(now);
+
+        while let Some(message) = c.shared.link.lock(|link| link.receive()) {
+            if c.shared.monitor.lock(|monitor| monitor.received(now_ms)) {
+                defmt::info!("Link up");
+                c.shared
+                    .indicators
+                    .lock(|indicators| indicators.link_up = true);
+                // The other half answers with the keys it holds
+                let side = c.shared.side.lock(|side| *side);
+                send_message::spawn(Message::Hello { side }).ok();
+            }
+            match message {
+                Message::Event {
+                    position,
+                    pressed,
+                    time_us,
+                } => {
+                    let event = KeyEvent { position, pressed };
+                    // Keys reported again after a re-sync may already be known
+                    if !c
+                        .shared
+                        .remote_keys
+                        .lock(|keys| keys.set(position, pressed))
+                    {
+                        continue;
+                    }
+                    let time_us = c.local.clock.to_local(time_us, now_us);
+                    c.shared.merger.lock(|merger| {
+                        if let Err(event) = merger.push(time_us, event) {
+                            handle_event::spawn(event, time_us).ok();
+                        }
+                    });
+                    // The scan releases the merged events
+                    resume_scan();
+                }
+                Message::Ping { origin_us } => {
+                    send_message::spawn(Message::Pong {
+                        origin_us,
+                        remote_us: now_us,
+                    })
+                    .ok();
+                }
+                Message::Pong {
+                    origin_us,
+                    remote_us,
+                } => {
+                    c.local.clock.update(origin_us, remote_us, now_us);
+                }
+                Message::Hello { side } => {
+                    let local_side = c.shared.side.lock(|side| *side);
+                    if side == local_side {
+                        defmt::error!(
+                            "Both halves report the {} side, check the side jumper",
+                            side
+                        );
+                    }
+                    // The other half released our keys when it lost the link
+                    let pressed = c.shared.local_keys.lock(|keys| keys.bits());
+                    send_message::spawn(Message::Heartbeat { pressed }).ok();
+                }
+                Message::Encoder { index, direction } => {
+                    handle_encoder::spawn(EncoderEvent { index, direction }).ok();
+                }
+                #[cfg(feature = "joystick")]
+                Message::Motion(motion) => {
+                    send_motion::spawn(motion).ok();
+                }
+                // Both halves are built with the same features
+                #[cfg(not(feature = "joystick"))]
+                Message::Motion(_) => {}
+                Message::Heartbeat { pressed } => {
+                    // Catches up with events lost on the way
+                    let keys = KeySet::from_bits(pressed);
+                    let previous = c
+                        .shared
+                        .remote_keys
+                        .lock(|remote_keys| core::mem::replace(remote_keys, keys));
+                    if previous != keys {
+                        sync_remote_keys::spawn().ok();
+                    }
+                }
+                Message::Token => {}
             }
         }
+        // Sends the queued frames if we just got the token
+        flush_link();
     }
 
-    #[task(priority = 3, capacity = 8, local = [intra_tx])]
-    fn send_event(c: send_event::Context, event: ButtonStatusEvent) {
-        println!("Sending event: {:?}", event);
-        let buf: [u8; 4] = (&event).into();
-        let tx = c.local.intra_tx;
-        tx.bwrite_all(&buf).and_then(|_| tx.bflush()).ok();
+    #[cfg(not(feature = "i2c-link"))]
+    #[task(binds = DMA2_STREAM2, priority = 5, shared = [rx_transfer])]
+    fn rx_dma(mut c: rx_dma::Context) {
+        c.shared
+            .rx_transfer
+            .lock(|transfer| transfer.clear_transfer_complete());
+        // The buffer is full, hand it over to the reception task
+        rtic::pend(Interrupt::USART1);
     }
 
-    #[task(priority = 3, capacity = 8, shared = [status_grid])]
-    fn handle_event(mut c: handle_event::Context, event: ButtonStatusEvent) {
-        println!("Event: {:?}", event);
-        c.shared.status_grid.lock(|status_grid| {
-            status_grid.set_pressed(event.out, event.inp, event.pressed);
-        })
+    #[task(priority = 3, capacity = 8, shared = [link])]
+    fn send_message(mut c: send_message::Context, message: Message) {
+        if !matches!(message, Message::Heartbeat { .. }) {
+            println!("Sending message: {:?}", message);
+        }
+        // Counted in the link statistics when dropped, the I²C link drops it when its queue is full
+        c.shared.link.lock(|link| link.send(&message)).ok();
+        flush_link();
+    }
+
+    /// Starts the DMA transmission of the queued bytes when the previous one is complete and the
+    /// half may use the bus, also pended by [`send_message`], [`handle_link`] and [`scan`]
+    #[cfg(not(feature = "i2c-link"))]
+    #[task(
+        binds = DMA2_STREAM7,
+        priority = 3,
+        shared = [link],
+        local = [tx_transfer, tx_spare, busy: bool = false]
+    )]
+    fn tx_dma(mut c: tx_dma::Context) {
+        let transfer = c.local.tx_transfer;
+        if transfer.is_transfer_complete() {
+            transfer.clear_transfer_complete();
+            *c.local.busy = false;
+        }
+        if *c.local.busy {
+            return;
+        }
+
+        let now_us = micros(monotonics::now());
+        let mut buffer = c.local.tx_spare.take().unwrap();
+        let len = c.shared.link.lock(|link| link.fill(&mut buffer, now_us));
+        if len > 0 {
+            let (sent, _) = transfer.next_transfer(buffer).unwrap();
+            buffer = sent;
+            *c.local.busy = true;
+        }
+        *c.local.tx_spare = Some(buffer);
+    }
+
+    /// Lets the single-wire link pass the bus token and notice when it is lost, also while the
+    /// matrix sleeps
+    #[cfg(feature = "half-duplex")]
+    #[task(priority = 3)]
+    fn pass_token(_: pass_token::Context) {
+        flush_link();
+        pass_token::spawn_after(Duration::micros(LINK_ARBITRATION.poll_us.into())).ok();
+    }
+
+    /// Writes the queued messages to the right half and reads its matrix, on the left half of the
+    /// I²C link.
+    ///
+    /// The transfers block, so they are done without holding the link, which the I²C interrupt
+    /// shares at a higher priority.
+    #[cfg(feature = "i2c-link")]
+    #[task(priority = 3, shared = [link], local = [i2c_bus])]
+    fn poll_link(mut c: poll_link::Context) {
+        let bus = c.local.i2c_bus.as_mut().unwrap();
+        while let Some(message) = c.shared.link.lock(|link| link.next_write()) {
+            // Lost when the right half does not answer
+            bus.write(&message).ok();
+        }
+        let now_us = micros(monotonics::now());
+        // A missing right half shows as a link down, for lack of heartbeats
+        if let Ok(registers) = bus.read() {
+            c.shared.link.lock(|link| link.update(&registers, now_us));
+        }
+        handle_link::spawn().ok();
+        poll_link::spawn_after(Duration::micros(LINK_POLL_PERIOD_US.into())).ok();
+    }
+
+    /// Bus events of the right half of the I²C link, the left half polls without interrupts
+    #[cfg(feature = "i2c-link")]
+    #[task(binds = I2C2_EV, priority = 5, shared = [link])]
+    fn i2c_event(mut c: i2c_event::Context) {
+        let i2c = unsafe { &*I2C2::ptr() };
+        let sr1 = i2c.sr1.read();
+        let stop = c.shared.link.lock(|link| {
+            let slave = match link.slave_mut() {
+                Some(slave) => slave,
+                None => return false,
+            };
+            if sr1.addr().bit_is_set() {
+                // Reading SR2 after SR1 clears ADDR
+                let sr2 = i2c.sr2.read();
+                slave.start(sr2.tra().bit_is_clear());
+            }
+            if sr1.rx_ne().bit_is_set() {
+                slave.write(i2c.dr.read().bits() as u8);
+            }
+            if sr1.tx_e().bit_is_set() {
+                i2c.dr.write(|w| unsafe { w.bits(slave.read().into()) });
+            }
+            sr1.stopf().bit_is_set()
+        });
+        if stop {
+            // Writing CR1 after reading SR1 clears STOPF
+            i2c.cr1.modify(|_, w| w);
+            handle_link::spawn().ok();
+        }
+    }
+
+    #[cfg(feature = "i2c-link")]
+    #[task(binds = I2C2_ER, priority = 5)]
+    fn i2c_error(_: i2c_error::Context) {
+        let i2c = unsafe { &*I2C2::ptr() };
+        // The master ends its reads with a NACK, the other errors drop the transfer
+        i2c.sr1.modify(|_, w| {
+            w.af()
+                .clear_bit()
+                .berr()
+                .clear_bit()
+                .arlo()
+                .clear_bit()
+                .ovr()
+                .clear_bit()
+        });
+    }
+
+    #[task(
+        priority = 3,
+        capacity = 16,
+        shared = [
+            bounce,
+            findings,
+            grid_keys,
+            heatmap,
+            indicators,
+            layers,
+            reports,
+            settings,
+            status_grid,
+            swallowed,
+        ],
+        local = [diagnostics: Diagnostics<4, 12> = Diagnostics::new(DIAGNOSTICS)]
+    )]
+    fn handle_event(mut c: handle_event::Context, event: KeyEvent, time_us: u32) {
+        println!("Event: {:?} at {} us", event, time_us);
+        let diagnostics = c.local.diagnostics;
+        // The microseconds of the event wrap around every 71 minutes, unlike the milliseconds
+        let finding = diagnostics.update(event, millis(monotonics::now()));
+        if let Some(finding) = finding {
+            match finding {
+                Finding::StuckAtBoot(position) => defmt::warn!("Key {} pressed at boot", position),
+                Finding::Chatter(position) => defmt::warn!("Key {} chatters", position),
+                _ => println!("Diagnostics: {}", finding),
+            }
+            // Sent when the host asks for them, dropped when the host never does
+            c.shared.findings.lock(|findings| {
+                findings.push_back(finding.encode()).ok();
+                if finding == Finding::Finished {
+                    for &(side, map) in &[(Side::Left, &LEFT_MAP), (Side::Right, &RIGHT_MAP)] {
+                        let untested = diagnostics.untested(map.keys()).count();
+                        println!("{} half: {} untested keys", side, untested);
+                        for position in diagnostics.untested(map.keys()) {
+                            println!("Untested key: {}", position);
+                        }
+                        findings
+                            .push_back(diagnostics.untested_report(side, map.keys()))
+                            .ok();
+                    }
+                }
+            });
+        }
+        if let Some(bounce_us) = c.shared.bounce.lock(|bounce| bounce.update(event, time_us)) {
+            defmt::warn!(
+                "Key {} bounced for {} us, longer than the debounce window",
+                event.position,
+                bounce_us
+            );
+        }
+
+        // The keys do not type during the test, nor do the combo presses starting and ending it
+        let swallow = matches!(finding, Some(Finding::Started) | Some(Finding::Finished))
+            || diagnostics.is_running();
+        c.shared
+            .swallowed
+            .lock(|swallowed| swallowed.set(event.position, swallow && event.pressed));
+        if finding == Some(Finding::Started) {
+            // The keys held to start the test are released, until they are pressed again
+            (
+                c.shared.grid_keys,
+                c.shared.indicators,
+                c.shared.layers,
+                c.shared.reports,
+                c.shared.settings,
+                c.shared.status_grid,
+                c.shared.swallowed,
+            )
+                .lock(
+                    |grid_keys, indicators, layers, reports, settings, status_grid, swallowed| {
+                        let held = *grid_keys;
+                        for position in held.iter() {
+                            swallowed.set(position, true);
+                            let event = KeyEvent {
+                                position,
+                                pressed: false,
+                            };
+                            apply_key(
+                                event,
+                                grid_keys,
+                                status_grid,
+                                layers,
+                                settings,
+                                indicators,
+                                reports,
+                            );
+                        }
+                    },
+                );
+        }
+        if swallow {
+            return;
+        }
+
+        let action = (
+            c.shared.grid_keys,
+            c.shared.heatmap,
+            c.shared.indicators,
+            c.shared.layers,
+            c.shared.reports,
+            c.shared.settings,
+            c.shared.status_grid,
+        )
+            .lock(
+                |grid_keys, heatmap, indicators, layers, reports, settings, status_grid| {
+                    let new = grid_keys.contains(event.position) != event.pressed;
+                    // The half connected to the host sees the keys of both halves
+                    if new && event.pressed && indicators.usb_configured {
+                        heatmap.record(layers.active(), event.position);
+                    }
+                    // Keys of the other half may be reconciled before their event
+                    apply_key(
+                        event,
+                        grid_keys,
+                        status_grid,
+                        layers,
+                        settings,
+                        indicators,
+                        reports,
+                    )
+                },
+            );
+        match action {
+            Some(Action::Bootloader) if event.pressed => bootloader::reset_to_bootloader(),
+            Some(Action::Reset) if event.pressed => cortex_m::peripheral::SCB::sys_reset(),
+            _ => {}
+        }
+    }
+
+    /// Brings the keys of the other half to the state it reported, without queueing an event
+    /// per key. The actions of the keys, like a reset, are not run.
+    #[task(
+        priority = 3,
+        shared = [
+            grid_keys,
+            indicators,
+            layers,
+            remote_keys,
+            reports,
+            settings,
+            side,
+            status_grid,
+            swallowed,
+        ]
+    )]
+    fn sync_remote_keys(mut c: sync_remote_keys::Context) {
+        let remote_map = match c.shared.side.lock(|side| *side) {
+            Side::Left => &RIGHT_MAP,
+            Side::Right => &LEFT_MAP,
+        };
+        let remote_keys = c.shared.remote_keys.lock(|keys| *keys);
+        let swallowed = c.shared.swallowed.lock(|keys| *keys);
+        (
+            c.shared.grid_keys,
+            c.shared.indicators,
+            c.shared.layers,
+            c.shared.reports,
+            c.shared.settings,
+            c.shared.status_grid,
+        )
+            .lock(
+                |grid_keys, indicators, layers, reports, settings, status_grid| {
+                    for position in remote_map.keys() {
+                        let pressed = remote_keys.contains(position);
+                        if grid_keys.contains(position) == pressed || swallowed.contains(position) {
+                            continue;
+                        }
+                        println!("Key {} of the other half reconciled", position);
+                        let event = KeyEvent { position, pressed };
+                        apply_key(
+                            event,
+                            grid_keys,
+                            status_grid,
+                            layers,
+                            settings,
+                            indicators,
+                            reports,
+                        );
+                    }
+                },
+            );
+        // The matrix may sleep while the other half is gone, the scan sends the reports
+        resume_scan();
+    }
+
+    #[task(priority = 3, capacity = 4, shared = [layers, reports, status_grid])]
+    fn handle_encoder(mut c: handle_encoder::Context, event: EncoderEvent) {
+        println!("Encoder: {:?}", event);
+        let action = c
+            .shared
+            .layers
+            .lock(|layers| ENCODER_MAP.resolve(layers.active(), layers.default_layer(), event));
+        match action {
+            Action::Key(code) => {
+                (c.shared.status_grid, c.shared.reports).lock(|status_grid, reports| {
+                    // Taps the key: pressed in one report, released in the next one
+                    let mut report: KeyboardReport = status_grid
+                        .to_report::<KeyboardReport, LedStatus, Infallible>()
+                        .unwrap();
+                    if (0xe0..=0xe7).contains(&code) {
+                        report.modifier |= 1 << (code - 0xe0);
+                    } else if let Some(slot) = report.keycodes.iter_mut().find(|k| **k == 0) {
+                        *slot = code;
+                    }
+                    reports.push(report);
+                    let report: KeyboardReport = status_grid
+                        .to_report::<KeyboardReport, LedStatus, Infallible>()
+                        .unwrap();
+                    reports.push(report);
+                });
+                // Steps of either half may come while the matrix sleeps, the scan sends the tap
+                resume_scan();
+            }
+            Action::Bootloader => bootloader::reset_to_bootloader(),
+            Action::Reset => cortex_m::peripheral::SCB::sys_reset(),
+            _ => {}
+        }
     }
 
-    #[task(priority = 3, shared = [usb_class, status_grid])]
+    #[task(priority = 3, shared = [usb_class, reports])]
     fn keyboard_tick(c: keyboard_tick::Context) {
-        (c.shared.usb_class, c.shared.status_grid).lock(|usb_class, status_grid| {
-            let report: KeyboardReport = status_grid
-                .to_report::<KeyboardReport, LedStatus, Infallible>()
-                .unwrap();
-            while let Ok(0) = usb_class.push_input(&report) {}
+        let now_ms = millis(monotonics::now());
+
+        (c.shared.usb_class, c.shared.reports).lock(|usb_class, reports| {
+            reports.send(now_ms, |report| usb_class.push_input(report));
+        })
+    }
+
+    #[cfg(feature = "joystick")]
+    #[task(
+        priority = 1,
+        shared = [indicators],
+        local = [joystick_adc, joystick: Joystick = Joystick::new(JOYSTICK)]
+    )]
+    fn sample_joystick(mut c: sample_joystick::Context) {
+        let (adc, (x, y)) = c.local.joystick_adc.as_mut().unwrap();
+        let x = adc.convert(x, SampleTime::Cycles_480);
+        let y = adc.convert(y, SampleTime::Cycles_480);
+        if let Some(motion) = c.local.joystick.update(x, y) {
+            // The half connected to the host reports the movement
+            if c.shared
+                .indicators
+                .lock(|indicators| indicators.usb_configured)
+            {
+                send_motion::spawn(motion).ok();
+            } else {
+                send_message::spawn(Message::Motion(motion)).ok();
+            }
+        }
+        sample_joystick::spawn_after(Duration::millis(JOYSTICK_PERIOD_MS.into())).ok();
+    }
+
+    #[cfg(feature = "joystick")]
+    #[task(
+        priority = 2,
+        capacity = 4,
+        shared = [indicators, mouse_class],
+        local = [pending: Motion = Motion { dx: 0, dy: 0 }]
+    )]
+    fn send_motion(c: send_motion::Context, motion: Motion) {
+        let pending = c.local.pending;
+        *pending = pending.merge(motion);
+        (c.shared.indicators, c.shared.mouse_class).lock(|indicators, mouse_class| {
+            // Dropped without host, kept for the next report while the host did not read the
+            // previous one
+            if !indicators.usb_configured
+                || mouse_class.as_mut().map_or(true, |class| {
+                    class.push_raw_input(&pending.report()).is_ok()
+                })
+            {
+                *pending = Motion::default();
+            }
         })
     }
 
-    #[task(binds = OTG_FS, priority = 2, shared = [usb_dev, usb_class])]
+    #[task(
+        priority = 1,
+        capacity = 2,
+        shared = [bounce, grid_keys, heatmap, layers, raw_class, settings, status_grid]
+    )]
+    fn handle_command(mut c: handle_command::Context, request: [u8; REPORT_SIZE]) {
+        let (response, effect) = if heatmap::is_heatmap_command(request[0]) {
+            c.shared
+                .heatmap
+                .lock(|heatmap| heatmap.handle_request(&request))
+        } else {
+            (
+                &mut c.shared.grid_keys,
+                &mut c.shared.layers,
+                &mut c.shared.settings,
+                c.shared.status_grid,
+            )
+                .lock(|grid_keys, layers, settings, status_grid| {
+                    let (response, effect) =
+                        protocol::handle_request(&request, settings, &DEFAULT_KEYMAP);
+                    if effect == Effect::KeymapChanged {
+                        let default = layers.default_layer();
+                        rebuild_grid(status_grid, settings, default, grid_keys);
+                    }
+                    (response, effect)
+                })
+        };
+
+        c.shared
+            .raw_class
+            .lock(|raw_class| raw_class.push_raw_input(&response))
+            .ok();
+
+        match effect {
+            Effect::KeymapChanged | Effect::SettingsChanged => {
+                save_settings::spawn().ok();
+            }
+            Effect::Bootloader => {
+                enter_bootloader::spawn_after(Duration::millis(BOOTLOADER_DELAY_MS.into())).ok();
+            }
+            Effect::BounceStats { enabled } => {
+                c.shared.bounce.lock(|bounce| bounce.set_enabled(enabled));
+                println!("Bounce statistics enabled: {}", enabled);
+            }
+            Effect::DumpBounceStats => {
+                dump_bounce_stats::spawn(0).ok();
+            }
+            Effect::DumpDiagnostics => {
+                dump_diagnostics::spawn().ok();
+            }
+            Effect::HeatmapChanged => {
+                save_heatmap::spawn(false).ok();
+            }
+            Effect::None => {}
+        }
+    }
+
+    #[task(priority = 1)]
+    fn enter_bootloader(_: enter_bootloader::Context) {
+        bootloader::reset_to_bootloader()
+    }
+
+    /// Sends the bounce statistics of the keys from the `index`-th one, one report at a time
+    #[task(priority = 1, shared = [bounce, raw_class])]
+    fn dump_bounce_stats(mut c: dump_bounce_stats::Context, index: usize) {
+        let (position, stats) = match c.shared.bounce.lock(|bounce| bounce.iter().nth(index)) {
+            Some(key) => key,
+            None => return,
+        };
+        let report = stats.encode(position);
+        let sent = c
+            .shared
+            .raw_class
+            .lock(|raw_class| raw_class.push_raw_input(&report));
+        if let Err(UsbError::WouldBlock) = sent {
+            // The host did not read the previous report yet
+            dump_bounce_stats::spawn_after(Duration::millis(1), index).ok();
+            return;
+        }
+        println!("Bounce of {}: {}", position, stats);
+        dump_bounce_stats::spawn(index + 1).ok();
+    }
+
+    /// Sends the findings of the matrix test kept for the host, one report at a time
+    #[task(priority = 1, shared = [findings, raw_class])]
+    fn dump_diagnostics(mut c: dump_diagnostics::Context) {
+        let report = match c.shared.findings.lock(|findings| findings.front().copied()) {
+            Some(report) => report,
+            None => return,
+        };
+        let sent = c
+            .shared
+            .raw_class
+            .lock(|raw_class| raw_class.push_raw_input(&report));
+        if let Err(UsbError::WouldBlock) = sent {
+            // The host did not read the previous report yet
+            dump_diagnostics::spawn_after(Duration::millis(1)).ok();
+            return;
+        }
+        c.shared.findings.lock(|findings| findings.pop_front());
+        dump_diagnostics::spawn().ok();
+    }
+
+    #[task(priority = 1, shared = [grid_keys, layers, settings, side, status_grid])]
+    fn handle_gesture(c: handle_gesture::Context, gesture: Gesture) {
+        println!("Button gesture: {:?}", gesture);
+        match gesture {
+            Gesture::Short => (
+                c.shared.grid_keys,
+                c.shared.layers,
+                c.shared.settings,
+                c.shared.status_grid,
+            )
+                .lock(|grid_keys, layers, settings, status_grid| {
+                    let default = layers.cycle_default();
+                    rebuild_grid(status_grid, settings, default, grid_keys);
+                    println!("Default layer: {}", default);
+                }),
+            Gesture::Long => bootloader::reset_to_bootloader(),
+            Gesture::Double => {
+                (c.shared.settings, c.shared.side).lock(|settings, side| {
+                    settings.side = Some(settings.side.unwrap_or(*side).opposite());
+                    println!("Stored side: {}, used from the next boot", settings.side);
+                });
+                save_settings::spawn().ok();
+            }
+        }
+    }
+
+    #[task(
+        priority = 1,
+        shared = [flash, settings],
+        local = [store, buf: [u8; KeyboardSettings::SIZE] = [0; KeyboardSettings::SIZE]]
+    )]
+    fn save_settings(mut c: save_settings::Context) {
+        let buf = c.local.buf;
+        let bytes = c.shared.settings.lock(|settings| settings.encode(buf));
+        let store = c.local.store;
+        // Programming stalls the flash, and with it the CPU, until completion
+        if c.shared
+            .flash
+            .lock(|flash| store.save(&mut flash.unlocked(), bytes))
+            .is_err()
+        {
+            println!("Failed to save the settings");
+        }
+    }
+
+    /// Saves the key press counters if they changed, `periodic` saves schedule the next one and
+    /// leave the store alone once it must be erased
+    #[task(
+        priority = 1,
+        capacity = 2,
+        shared = [flash, heatmap],
+        local = [heatmap_store, buf: [u8; KeyboardHeatmap::SIZE] = [0; KeyboardHeatmap::SIZE]]
+    )]
+    fn save_heatmap(mut c: save_heatmap::Context, periodic: bool) {
+        if periodic {
+            let period = Duration::millis(HEATMAP_SAVE_PERIOD_MS.into());
+            save_heatmap::spawn_after(period, true).ok();
+        }
+        let store = c.local.heatmap_store;
+        // Erasing the 128 KiB sector stalls the CPU for 1 to 2 s, the host asks for it instead
+        if periodic
+            && !c
+                .shared
+                .flash
+                .lock(|flash| store.fits(flash, KeyboardHeatmap::SIZE))
+                .unwrap_or(false)
+        {
+            defmt::debug!("Heatmap store full, saved on the next host request");
+            return;
+        }
+        let buf = c.local.buf;
+        let bytes = c
+            .shared
+            .heatmap
+            .lock(|heatmap| heatmap.take_dirty().then(|| heatmap.encode(buf)));
+        if let Some(bytes) = bytes {
+            if c.shared
+                .flash
+                .lock(|flash| store.save(&mut flash.unlocked(), bytes))
+                .is_err()
+            {
+                println!("Failed to save the heatmap");
+            }
+        }
+    }
+
+    #[task(priority = 1)]
+    fn sync_clock(_: sync_clock::Context) {
+        send_message::spawn(Message::Ping {
+            origin_us: micros(monotonics::now()),
+        })
+        .ok();
+        sync_clock::spawn_after(Duration::millis(CLOCK_SYNC_PERIOD_MS.into())).ok();
+    }
+
+    #[task(priority = 1, shared = [indicators, local_keys, monitor, remote_keys])]
+    fn heartbeat(mut c: heartbeat::Context) {
+        let now = monotonics::now();
+        let pressed = c.shared.local_keys.lock(|keys| keys.bits());
+        send_message::spawn(Message::Heartbeat { pressed }).ok();
+
+        match c.shared.monitor.lock(|monitor| monitor.update(millis(now))) {
+            Some(LinkState::Degraded) => defmt::warn!("Link degraded"),
+            Some(LinkState::Down) => {
+                defmt::warn!("Link down, releasing the keys of the other half");
+                c.shared
+                    .indicators
+                    .lock(|indicators| indicators.link_up = false);
+                c.shared.remote_keys.lock(|keys| *keys = KeySet::new());
+                sync_remote_keys::spawn().ok();
+            }
+            _ => {}
+        }
+        heartbeat::spawn_after(Duration::millis(HEARTBEAT_PERIOD_MS.into())).ok();
+    }
+
+    #[cfg(not(feature = "i2c-link"))]
+    #[task(priority = 1, shared = [link], local = [last: LinkStats = LinkStats::new()])]
+    fn log_link(mut c: log_link::Context) {
+        let stats = c.shared.link.lock(|link| link.stats());
+        let delta = stats.since(c.local.last);
+        *c.local.last = stats;
+        defmt::info!(
+            "Link: {} B/s received, {} B/s sent, {}",
+            delta.rx_bytes * 1000 / LINK_STATS_PERIOD_MS,
+            delta.tx_bytes * 1000 / LINK_STATS_PERIOD_MS,
+            stats
+        );
+        log_link::spawn_after(Duration::millis(LINK_STATS_PERIOD_MS.into())).ok();
+    }
+
+    #[task(priority = 1, shared = [indicators], local = [led, led_config])]
+    fn led_tick(mut c: led_tick::Context) {
+        let now_ms = millis(monotonics::now());
+        let config = c.local.led_config;
+        let pattern = c
+            .shared
+            .indicators
+            .lock(|indicators| config.pattern(indicators));
+
+        // The LED is on when PC13 is low
+        if pattern.is_on(now_ms) {
+            c.local.led.set_low();
+        } else {
+            c.local.led.set_high();
+        }
+
+        led_tick::spawn_after(Duration::millis(LED_TICK_MS.into())).ok();
+    }
+
+    #[task(
+        binds = OTG_FS,
+        priority = 2,
+        shared = [usb_dev, usb_class, raw_class, mouse_class, indicators]
+    )]
     fn usb_tx(cx: usb_tx::Context) {
-        (cx.shared.usb_dev, cx.shared.usb_class).lock(usb_poll);
+        (
+            cx.shared.usb_dev,
+            cx.shared.usb_class,
+            cx.shared.raw_class,
+            cx.shared.mouse_class,
+            cx.shared.indicators,
+        )
+            .lock(usb_poll);
     }
 
-    #[task(binds = OTG_FS_WKUP, priority = 2, shared = [usb_dev, usb_class])]
+    #[task(
+        binds = OTG_FS_WKUP,
+        priority = 2,
+        shared = [usb_dev, usb_class, raw_class, mouse_class, indicators]
+    )]
     fn usb_rx(cx: usb_rx::Context) {
-        (cx.shared.usb_dev, cx.shared.usb_class).lock(usb_poll);
+        (
+            cx.shared.usb_dev,
+            cx.shared.usb_class,
+            cx.shared.raw_class,
+            cx.shared.mouse_class,
+            cx.shared.indicators,
+        )
+            .lock(usb_poll);
     }
 
-    fn usb_poll(usb_dev: &mut UsbDevice, keyboard: &mut UsbKeyboardClass) {
-        if usb_dev.poll(&mut [keyboard]) {
+    fn usb_poll(
+        usb_dev: &mut UsbDevice,
+        keyboard: &mut UsbKeyboardClass,
+        raw: &mut UsbRawClass,
+        mouse: &mut Option<UsbMouseClass>,
+        indicators: &mut Indicators,
+    ) {
+        let polled = match mouse {
+            Some(mouse) => usb_dev.poll(&mut [keyboard, raw, mouse]),
+            None => usb_dev.poll(&mut [keyboard, raw]),
+        };
+        if polled {
             keyboard.poll();
+
+            // Output report of the keyboard interface, bit 1 is Caps Lock
+            let mut leds = [0; 1];
+            if let Ok(1) = keyboard.pull_raw_output(&mut leds) {
+                indicators.caps_lock = leds[0] & 0x02 != 0;
+            }
+
+            let mut report = [0; REPORT_SIZE];
+            if let Ok(REPORT_SIZE) = raw.pull_raw_output(&mut report) {
+                handle_command::spawn(report).ok();
+            }
         }
+        indicators.usb_configured = usb_dev.state() == UsbDeviceState::Configured;
     }
 }
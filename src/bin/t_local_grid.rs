@@ -6,7 +6,8 @@ use lets_split as _; // global logger + panicking-behavior + memory layout
 #[rtic::app(device = stm32f4xx_hal::pac, peripherals = true)]
 mod app {
     use defmt::info;
-    use keyboard_io::{buttons::LocalGrid, prelude::PinState};
+    use embedded_hal::blocking::delay::DelayUs;
+    use lets_split::matrix::{DiodeDirection, Matrix, MatrixConfig, Pull};
     use stm32f4xx_hal::{
         gpio::{EPin, Input, Output, PushPull},
         interrupt, pac,
@@ -14,6 +15,27 @@ mod app {
         timer,
     };
 
+    /// Core clock
+    const SYSCLK_HZ: u32 = 84_000_000;
+
+    /// Wiring of the grid: the rows are read with pull-ups, the columns driven low
+    const MATRIX: MatrixConfig = MatrixConfig {
+        diodes: DiodeDirection::Row2Col,
+        pull: Pull::Up,
+        select_delay_us: 1,
+        unselect_delay_us: 30,
+        ghost_filter: false,
+    };
+
+    /// Busy wait counted in core cycles, for the settle times of the matrix
+    struct SpinDelay;
+
+    impl DelayUs<u32> for SpinDelay {
+        fn delay_us(&mut self, us: u32) {
+            cortex_m::asm::delay(us * (SYSCLK_HZ / 1_000_000));
+        }
+    }
+
     // Shared resources go here
     #[shared]
     struct Shared {}
@@ -21,7 +43,7 @@ mod app {
     // Local resources go here
     #[local]
     struct Local {
-        local_grid: LocalGrid<EPin<Input>, EPin<Output<PushPull>>, 2, 3>,
+        matrix: Matrix<EPin<Input>, EPin<Output<PushPull>>, 2, 3>,
         timer: timer::CounterUs<pac::TIM3>,
     }
 
@@ -58,9 +80,9 @@ mod app {
             gpioa.pa5.into_push_pull_output().erase(),
         ];
 
-        let local_grid = LocalGrid::new(inputs, outputs, PinState::Low);
+        let matrix = Matrix::new(MATRIX, inputs, outputs);
 
-        (Shared {}, Local { local_grid, timer }, init::Monotonics())
+        (Shared {}, Local { matrix, timer }, init::Monotonics())
     }
 
     // Optional idle, can be removed if not needed.
@@ -75,11 +97,11 @@ mod app {
         }
     }
 
-    #[task(binds = TIM3, priority = 1, local = [timer, local_grid])]
+    #[task(binds = TIM3, priority = 1, local = [timer, matrix])]
     fn tick(c: tick::Context) {
         c.local.timer.wait().ok();
 
-        for e in c.local.local_grid.get_events() {
+        for e in c.local.matrix.scan(&mut SpinDelay) {
             info!("Event: {:?}", e);
         }
     }
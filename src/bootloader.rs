@@ -0,0 +1,43 @@
+//! Reset into the STM32 system memory DFU bootloader.
+//!
+//! A magic value is written to a RAM word that the runtime never initializes, then the MCU is
+//! reset. Early at the next boot, before any peripheral is configured, the value is checked and
+//! the execution is handed to the bootloader in system memory.
+
+use core::{mem::MaybeUninit, ptr};
+use stm32f4xx_hal::pac;
+
+/// Value marking a bootloader request
+const MAGIC: u32 = 0xb007_10ad;
+
+/// Vector table of the system memory bootloader
+const SYSTEM_MEMORY: u32 = 0x1fff_0000;
+
+#[link_section = ".uninit.BOOTLOADER_REQUEST"]
+static mut REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+/// Resets the MCU into the DFU bootloader
+pub fn reset_to_bootloader() -> ! {
+    unsafe { ptr::write_volatile(ptr::addr_of_mut!(REQUEST).cast::<u32>(), MAGIC) };
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Runs before RAM initialization and jumps to the bootloader if requested
+#[cortex_m_rt::pre_init]
+unsafe fn jump_if_requested() {
+    let request = ptr::addr_of_mut!(REQUEST).cast::<u32>();
+    if ptr::read_volatile(request) != MAGIC {
+        return;
+    }
+    ptr::write_volatile(request, 0);
+
+    // Map the system memory at address 0, as it is after a boot with BOOT0 high
+    (*pac::RCC::ptr())
+        .apb2enr
+        .modify(|_, w| w.syscfgen().set_bit());
+    (*pac::SYSCFG::ptr())
+        .memrmp
+        .modify(|_, w| w.mem_mode().bits(0b01));
+
+    cortex_m::asm::bootload(SYSTEM_MEMORY as *const u32)
+}
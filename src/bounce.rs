@@ -0,0 +1,188 @@
+//! Bounce statistics of the switches, to choose a debounce time.
+//!
+//! [`BounceStats`] counts the transitions of every key from the scan timestamps carried by the
+//! key events. Transitions of a key closer than [`BounceConfig::burst_us`] to the previous one
+//! are bounces of the same burst, the bounce time being the span of the burst. Keys bouncing for
+//! longer than [`BounceConfig::debounce_us`] are flagged.
+//!
+//! The statistics are collected only once enabled. The firmware dumps them over defmt and to the
+//! host as raw HID input reports of [`REPORT_SIZE`] bytes, one per key, multi-byte values being
+//! big endian:
+//!
+//! | Byte   | Content                    |
+//! |--------|----------------------------|
+//! | 0      | [`REPORT_ID`]              |
+//! | 1, 2   | row and column of the key  |
+//! | 3      | 1 if the key is flagged    |
+//! | 4..6   | presses (u16)              |
+//! | 6..8   | releases (u16)             |
+//! | 8..10  | bounces (u16)              |
+//! | 10..14 | longest bounce in us (u32) |
+
+use crate::{
+    position::{KeyEvent, KeyPosition},
+    protocol::REPORT_SIZE,
+};
+
+/// First byte of the raw HID reports of the bounce statistics
+pub const REPORT_ID: u8 = 0xf1;
+
+/// Time windows of the bounce statistics
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BounceConfig {
+    /// Longest time between two transitions of a bouncing key
+    pub burst_us: u32,
+    /// Debounce time under evaluation
+    pub debounce_us: u32,
+}
+
+impl Default for BounceConfig {
+    fn default() -> Self {
+        Self {
+            burst_us: 20_000,
+            debounce_us: 5_000,
+        }
+    }
+}
+
+/// Statistics of a key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub struct KeyBounce {
+    pub presses: u16,
+    pub releases: u16,
+    /// Transitions following the previous one within the burst window
+    pub bounces: u16,
+    /// Longest bounce time
+    pub max_bounce_us: u32,
+    /// The key bounced for longer than the debounce time
+    pub flagged: bool,
+}
+
+impl KeyBounce {
+    const NEW: Self = Self {
+        presses: 0,
+        releases: 0,
+        bounces: 0,
+        max_bounce_us: 0,
+        flagged: false,
+    };
+
+    /// Encodes the raw HID report of the statistics of the key at `position`
+    pub fn encode(&self, position: KeyPosition) -> [u8; REPORT_SIZE] {
+        let mut report = [0; REPORT_SIZE];
+        report[0..4].copy_from_slice(&[
+            REPORT_ID,
+            position.row.0,
+            position.col.0,
+            self.flagged as u8,
+        ]);
+        report[4..6].copy_from_slice(&self.presses.to_be_bytes());
+        report[6..8].copy_from_slice(&self.releases.to_be_bytes());
+        report[8..10].copy_from_slice(&self.bounces.to_be_bytes());
+        report[10..14].copy_from_slice(&self.max_bounce_us.to_be_bytes());
+        report
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct KeyState {
+    stats: KeyBounce,
+    /// Time of the last transition
+    last_us: Option<u32>,
+    /// Time of the first transition of the current burst
+    burst_start_us: u32,
+}
+
+impl KeyState {
+    const NEW: Self = Self {
+        stats: KeyBounce::NEW,
+        last_us: None,
+        burst_start_us: 0,
+    };
+}
+
+/// Bounce statistics of the keys of a keyboard of `R` rows and `C` columns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BounceStats<const R: usize, const C: usize> {
+    config: BounceConfig,
+    enabled: bool,
+    keys: [[KeyState; C]; R],
+}
+
+impl<const R: usize, const C: usize> BounceStats<R, C> {
+    /// Disabled statistics
+    pub const fn new(config: BounceConfig) -> Self {
+        Self {
+            config,
+            enabled: false,
+            keys: [[KeyState::NEW; C]; R],
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Starts collecting from scratch, or stops collecting and keeps the statistics
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled && !self.enabled {
+            self.keys = [[KeyState::NEW; C]; R];
+        }
+        self.enabled = enabled;
+    }
+
+    /// Counts a transition at `time_us`, returns the bounce time when the key is flagged by it
+    pub fn update(&mut self, event: KeyEvent, time_us: u32) -> Option<u32> {
+        if !self.enabled {
+            return None;
+        }
+        let key = self
+            .keys
+            .get_mut(event.position.row.index())?
+            .get_mut(event.position.col.index())?;
+        let stats = &mut key.stats;
+        if event.pressed {
+            stats.presses = stats.presses.saturating_add(1);
+        } else {
+            stats.releases = stats.releases.saturating_add(1);
+        }
+
+        let burst_us = self.config.burst_us;
+        let bouncing = key
+            .last_us
+            .filter(|&last| time_us.wrapping_sub(last) < burst_us)
+            .is_some();
+        key.last_us = Some(time_us);
+        if !bouncing {
+            key.burst_start_us = time_us;
+            return None;
+        }
+
+        stats.bounces = stats.bounces.saturating_add(1);
+        let bounce_us = time_us.wrapping_sub(key.burst_start_us);
+        stats.max_bounce_us = stats.max_bounce_us.max(bounce_us);
+        if stats.flagged || bounce_us <= self.config.debounce_us {
+            return None;
+        }
+        stats.flagged = true;
+        Some(bounce_us)
+    }
+
+    /// Statistics of the key at `position`
+    pub fn get(&self, position: KeyPosition) -> Option<&KeyBounce> {
+        self.keys
+            .get(position.row.index())
+            .and_then(|row| row.get(position.col.index()))
+            .map(|key| &key.stats)
+    }
+
+    /// Keys that changed state at least once, row by row
+    pub fn iter(&self) -> impl Iterator<Item = (KeyPosition, KeyBounce)> + '_ {
+        self.keys.iter().enumerate().flat_map(|(row, keys)| {
+            keys.iter()
+                .enumerate()
+                .filter(|(_, key)| key.last_us.is_some())
+                .map(move |(col, key)| (KeyPosition::new(row as u8, col as u8), key.stats))
+        })
+    }
+}
@@ -0,0 +1,63 @@
+//! Offset between the clocks of the two halves.
+//!
+//! A half sends a [`Ping`](crate::link::Message::Ping) with its time, the other half answers
+//! with the ping time and its own time at reception. Assuming symmetric link delays, the remote
+//! clock read half a round trip after the ping, which gives the offset between the clocks.
+//! Samples with the shortest round trip are the most accurate, so longer ones are ignored until
+//! the best round trip has aged enough to follow the drift of the clocks.
+
+/// Increase of the best round trip for every ignored sample
+const ROUND_TRIP_AGING_US: u32 = 500;
+
+/// Estimated offset of the remote clock, all times in microseconds
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClockOffset {
+    /// Remote time minus local time
+    offset: Option<u32>,
+    /// Round trip of the sample the offset comes from
+    round_trip_us: u32,
+}
+
+impl ClockOffset {
+    pub const fn new() -> Self {
+        Self {
+            offset: None,
+            round_trip_us: 0,
+        }
+    }
+
+    /// Remote time minus local time, `None` before the first sample
+    pub fn offset_us(&self) -> Option<i32> {
+        self.offset.map(|offset| offset as i32)
+    }
+
+    /// Adds the answer to a ping sent at `origin_us`, read by the other half at `remote_us` and
+    /// received at `now_us`
+    pub fn update(&mut self, origin_us: u32, remote_us: u32, now_us: u32) {
+        let round_trip_us = now_us.wrapping_sub(origin_us);
+        let best_us = self.round_trip_us.saturating_add(ROUND_TRIP_AGING_US);
+        if self.offset.is_none() || round_trip_us <= best_us {
+            self.offset = Some(remote_us.wrapping_sub(origin_us.wrapping_add(round_trip_us / 2)));
+            self.round_trip_us = round_trip_us;
+        } else {
+            self.round_trip_us = best_us;
+        }
+    }
+
+    /// Converts a remote time to the local clock.
+    ///
+    /// Times are never later than `now_us`, which is also used before the first sample.
+    pub fn to_local(&self, remote_us: u32, now_us: u32) -> u32 {
+        match self.offset {
+            Some(offset) => {
+                let local_us = remote_us.wrapping_sub(offset);
+                if (now_us.wrapping_sub(local_us) as i32) < 0 {
+                    now_us
+                } else {
+                    local_us
+                }
+            }
+            None => now_us,
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! Hardware test of the key matrix, run by the firmware itself.
+//!
+//! [`Diagnostics`] watches the key events of the whole keyboard. Keys pressed at boot are
+//! reported as stuck right away. Holding every key of the combo of the [`DiagnosticConfig`]
+//! starts the test: each key is then reported at its first press, and once if it is pressed
+//! again too shortly after its release, which denotes a chattering switch. Holding the combo
+//! again ends the test, [`Diagnostics::untested`] lists the keys of each half never pressed.
+//!
+//! The firmware logs the [`Finding`]s over defmt and keeps them for the host, which reads them
+//! with the `DumpDiagnostics` command of the [`protocol`](crate::protocol) as raw HID input
+//! reports of [`REPORT_SIZE`] bytes:
+//!
+//! | Byte | Content                                                                |
+//! |------|------------------------------------------------------------------------|
+//! | 0    | [`REPORT_ID`]                                                          |
+//! | 1    | kind: 0 started, 1 finished, 2 new key, 3 chatter, 4 stuck, 5 untested |
+//! | 2..  | payload                                                                |
+//!
+//! Keys are sent as their row and column. The untested keys of a half are sent as the side of
+//! the half, their number and a bitmap of the keys of the keyboard, bit `row * C + col`.
+
+use crate::{
+    health::KeySet,
+    position::{KeyEvent, KeyPosition},
+    protocol::REPORT_SIZE,
+    side::Side,
+};
+
+/// First byte of the raw HID reports of the diagnostics, apart from the protocol commands
+pub const REPORT_ID: u8 = 0xf0;
+
+const KIND_UNTESTED: u8 = 5;
+
+/// Timings and entry combo of the diagnostics
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiagnosticConfig {
+    /// Keys pressed before this time since boot are stuck
+    pub boot_ms: u32,
+    /// Shortest time between the release of a key and its next press
+    pub chatter_ms: u32,
+    /// Keys to hold together to start or end the test
+    pub combo: &'static [KeyPosition],
+}
+
+/// Something the diagnostics report
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Finding {
+    /// The test started
+    Started,
+    /// The test ended, the untested keys are to be reported
+    Finished,
+    /// First press of the key since the start of the test
+    NewKey(KeyPosition),
+    /// The key was pressed again too shortly after its release
+    Chatter(KeyPosition),
+    /// The key was pressed at boot
+    StuckAtBoot(KeyPosition),
+}
+
+impl Finding {
+    /// Encodes the raw HID report of the finding
+    pub fn encode(&self) -> [u8; REPORT_SIZE] {
+        let mut report = [0; REPORT_SIZE];
+        report[0] = REPORT_ID;
+        let (kind, position) = match *self {
+            Finding::Started => (0, None),
+            Finding::Finished => (1, None),
+            Finding::NewKey(position) => (2, Some(position)),
+            Finding::Chatter(position) => (3, Some(position)),
+            Finding::StuckAtBoot(position) => (4, Some(position)),
+        };
+        report[1] = kind;
+        if let Some(position) = position {
+            report[2..4].copy_from_slice(&[position.row.0, position.col.0]);
+        }
+        report
+    }
+}
+
+/// Hardware test of a keyboard of `R` rows and `C` columns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Diagnostics<const R: usize, const C: usize> {
+    config: DiagnosticConfig,
+    running: bool,
+    /// Set once an event came after the boot window
+    booted: bool,
+    held: KeySet<R, C>,
+    tested: KeySet<R, C>,
+    chattering: KeySet<R, C>,
+    /// Time of the last release of each key
+    released_ms: [[Option<u32>; C]; R],
+}
+
+impl<const R: usize, const C: usize> Diagnostics<R, C> {
+    pub const fn new(config: DiagnosticConfig) -> Self {
+        Self {
+            config,
+            running: false,
+            booted: false,
+            held: KeySet::new(),
+            tested: KeySet::new(),
+            chattering: KeySet::new(),
+            released_ms: [[None; C]; R],
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Handles a key event at `time_ms` since boot, returns what it revealed
+    pub fn update(&mut self, event: KeyEvent, time_ms: u32) -> Option<Finding> {
+        let KeyEvent { position, pressed } = event;
+        if !self.booted {
+            if time_ms >= self.config.boot_ms {
+                self.booted = true;
+            } else if pressed {
+                return Some(Finding::StuckAtBoot(position));
+            }
+        }
+
+        self.held.set(position, pressed);
+        let combo = self.config.combo;
+        let combo_held =
+            combo.contains(&position) && combo.iter().all(|&key| self.held.contains(key));
+        if pressed && combo_held {
+            self.running = !self.running;
+            if !self.running {
+                return Some(Finding::Finished);
+            }
+            self.tested = KeySet::new();
+            self.chattering = KeySet::new();
+            self.released_ms = [[None; C]; R];
+            return Some(Finding::Started);
+        }
+
+        let released_ms = self
+            .released_ms
+            .get_mut(position.row.index())
+            .and_then(|row| row.get_mut(position.col.index()))?;
+        if !pressed {
+            *released_ms = Some(time_ms);
+            return None;
+        }
+        if !self.running {
+            return None;
+        }
+        let chatter = released_ms
+            .filter(|&released| time_ms.wrapping_sub(released) < self.config.chatter_ms)
+            .is_some();
+        if chatter && self.chattering.set(position, true) {
+            Some(Finding::Chatter(position))
+        } else if self.tested.set(position, true) {
+            Some(Finding::NewKey(position))
+        } else {
+            None
+        }
+    }
+
+    /// Keys among `keys` not pressed since the start of the test
+    pub fn untested<'a>(
+        &'a self,
+        keys: impl IntoIterator<Item = KeyPosition> + 'a,
+    ) -> impl Iterator<Item = KeyPosition> + 'a {
+        keys.into_iter()
+            .filter(move |&key| !self.tested.contains(key))
+    }
+
+    /// Encodes the raw HID report of the untested keys among `keys`, those of the `side` half
+    pub fn untested_report(
+        &self,
+        side: Side,
+        keys: impl IntoIterator<Item = KeyPosition>,
+    ) -> [u8; REPORT_SIZE] {
+        let mut report = [0; REPORT_SIZE];
+        report[0..3].copy_from_slice(&[REPORT_ID, KIND_UNTESTED, side.to_byte()]);
+        for key in self.untested(keys) {
+            let bit = key.row.index() * C + key.col.index();
+            if let Some(byte) = report.get_mut(4 + bit / 8) {
+                *byte |= 1 << (bit % 8);
+                report[3] += 1;
+            }
+        }
+        report
+    }
+}
@@ -0,0 +1,89 @@
+//! Buffers of the DMA driven serial link.
+//!
+//! Frames to send are queued in a [`TxQueue`] ring buffer. When the DMA stream is done, the
+//! queued bytes are moved to a [`TxBuffer`], handed over to the DMA for the next transfer.
+
+use embedded_dma::ReadBuffer;
+use heapless::Deque;
+
+/// Ring buffer of the bytes waiting for transmission
+#[derive(Clone, Debug, Default)]
+pub struct TxQueue<const N: usize> {
+    bytes: Deque<u8, N>,
+}
+
+impl<const N: usize> TxQueue<N> {
+    pub const fn new() -> Self {
+        Self {
+            bytes: Deque::new(),
+        }
+    }
+
+    /// Queues a whole frame, returns false and drops it if there is not enough room
+    pub fn push(&mut self, frame: &[u8]) -> bool {
+        if N - self.bytes.len() < frame.len() {
+            return false;
+        }
+        for &byte in frame {
+            self.bytes.push_back(byte).ok();
+        }
+        true
+    }
+
+    /// Returns true if there is nothing to send
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Moves the oldest bytes to `buf`, returns how many were moved
+    pub fn pop_into(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        for slot in buf {
+            match self.bytes.pop_front() {
+                Some(byte) => *slot = byte,
+                None => break,
+            }
+            len += 1;
+        }
+        len
+    }
+}
+
+/// Static buffer of which the DMA sends only the filled part
+#[derive(Debug)]
+pub struct TxBuffer<const N: usize> {
+    buf: &'static mut [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> TxBuffer<N> {
+    pub fn new(buf: &'static mut [u8; N]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    /// Replaces the content with `bytes`, which must fit in the buffer
+    pub fn set(&mut self, bytes: &[u8]) {
+        self.buf[..bytes.len()].copy_from_slice(bytes);
+        self.len = bytes.len();
+    }
+
+    /// Replaces the content with at most `limit` of the oldest bytes of `queue`, returns how
+    /// many were moved
+    pub fn fill<const Q: usize>(&mut self, queue: &mut TxQueue<Q>, limit: usize) -> usize {
+        self.len = queue.pop_into(&mut self.buf[..limit.min(N)]);
+        self.len
+    }
+
+    /// Bytes to send
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+unsafe impl<const N: usize> ReadBuffer for TxBuffer<N> {
+    type Word = u8;
+
+    unsafe fn read_buffer(&self) -> (*const u8, usize) {
+        (self.buf.as_ptr(), self.len)
+    }
+}
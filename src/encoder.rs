@@ -0,0 +1,128 @@
+//! Rotary encoders such as the EC11, read from their two quadrature outputs.
+//!
+//! [`Quadrature`] follows the Gray code of the A and B outputs, sampled by the matrix scan. A
+//! bouncing output only moves the code back and forth, and codes where both outputs changed at
+//! once are ignored. A rotation is reported once every [`Quadrature::new`] steps, the steps
+//! between two detents of the encoder.
+//!
+//! The encoders of both halves are numbered on the keyboard, like the keys are placed by a
+//! [`HalfMap`](crate::position::HalfMap). The [`EncoderMap`] gives the action of each direction
+//! of each encoder per layer.
+
+use crate::keymap::Action;
+
+/// Direction of a rotation, seen from the knob
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Direction {
+    /// The A output changes before the B output
+    Clockwise,
+    CounterClockwise,
+}
+
+impl Direction {
+    /// Encodes the direction in a byte
+    pub const fn to_byte(self) -> u8 {
+        match self {
+            Direction::Clockwise => 0,
+            Direction::CounterClockwise => 1,
+        }
+    }
+
+    /// Decodes a byte encoded by [`Direction::to_byte`]
+    pub const fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Direction::Clockwise),
+            1 => Some(Direction::CounterClockwise),
+            _ => None,
+        }
+    }
+}
+
+/// A rotation of the encoder at `index` on the keyboard
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct EncoderEvent {
+    pub index: u8,
+    pub direction: Direction,
+}
+
+/// Step between two Gray codes `(a << 1) | b`, indexed by `(previous << 2) | current`.
+///
+/// Clockwise the code goes 0, 2, 3, 1, unchanged and invalid codes count 0.
+const STEPS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+/// Decoder of the quadrature outputs of an encoder
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quadrature {
+    steps_per_detent: i8,
+    /// Code of the last sample, `None` before the first one
+    code: Option<u8>,
+    /// Steps since the last reported rotation, positive clockwise
+    steps: i8,
+}
+
+impl Quadrature {
+    /// Decoder of an encoder with `steps_per_detent` Gray code steps between two detents, 4 for
+    /// most EC11
+    pub const fn new(steps_per_detent: u8) -> Self {
+        Self {
+            steps_per_detent: steps_per_detent as i8,
+            code: None,
+            steps: 0,
+        }
+    }
+
+    /// Samples the A and B outputs, returns the direction once a detent is reached
+    pub fn update(&mut self, a: bool, b: bool) -> Option<Direction> {
+        let code = (a as u8) << 1 | b as u8;
+        let previous = self.code.replace(code)?;
+        self.steps += STEPS[usize::from(previous << 2 | code)];
+        if self.steps >= self.steps_per_detent {
+            self.steps = 0;
+            Some(Direction::Clockwise)
+        } else if self.steps <= -self.steps_per_detent {
+            self.steps = 0;
+            Some(Direction::CounterClockwise)
+        } else {
+            None
+        }
+    }
+}
+
+/// Actions of `E` encoders on `L` layers, clockwise first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncoderMap<const E: usize, const L: usize> {
+    layers: [[[Action; 2]; E]; L],
+}
+
+impl<const E: usize, const L: usize> EncoderMap<E, L> {
+    /// Creates an encoder map from its layers
+    pub const fn new(layers: [[[Action; 2]; E]; L]) -> Self {
+        Self { layers }
+    }
+
+    /// Number of encoders of the keyboard
+    pub const fn encoder_count(&self) -> usize {
+        E
+    }
+
+    /// Action of a rotation on `layer`, [`Action::No`] for unknown encoders.
+    ///
+    /// Transparent actions fall through to the default layer, then to the base layer, like the
+    /// ones of the [`Keymap`](crate::keymap::Keymap).
+    pub fn resolve(&self, layer: usize, default: usize, event: EncoderEvent) -> Action {
+        let direction = match event.direction {
+            Direction::Clockwise => 0,
+            Direction::CounterClockwise => 1,
+        };
+        [layer, default, 0]
+            .iter()
+            .filter_map(|&layer| {
+                self.layers
+                    .get(layer)?
+                    .get(usize::from(event.index))
+                    .map(|actions| actions[direction])
+            })
+            .find(|&action| action != Action::Transparent)
+            .unwrap_or(Action::No)
+    }
+}
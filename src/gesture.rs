@@ -0,0 +1,134 @@
+//! Short, long and double press detection for a push button.
+//!
+//! The detector is fed with the edges of the button and the current time in milliseconds. An
+//! edge is accepted once the button kept its new state for [`GestureConfig::debounce_ms`], so
+//! that a bouncing press is not taken for a double press. A short press is reported only once
+//! the double press window expired, a long press as soon as the button is held for long enough.
+
+/// Gesture performed on the button
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Gesture {
+    Short,
+    Long,
+    Double,
+}
+
+/// Timing thresholds of the gestures
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GestureConfig {
+    /// Time the button must keep its state before the edge is accepted
+    pub debounce_ms: u32,
+    /// Hold time of a long press
+    pub long_press_ms: u32,
+    /// Longest time between the release of a press and the start of the next one for them to be
+    /// a double press
+    pub double_press_ms: u32,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        Self {
+            debounce_ms: 20,
+            long_press_ms: 1000,
+            double_press_ms: 300,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// Pressed at the given time, `second` if it follows a short press
+    Pressed {
+        since: u32,
+        second: bool,
+    },
+    /// Released after a short press, waiting for a second press
+    Released {
+        at: u32,
+    },
+    /// Long press reported, waiting for the release
+    Held,
+}
+
+/// Turns button edges into [`Gesture`]s
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GestureDetector {
+    config: GestureConfig,
+    state: State,
+    /// Accepted state of the button
+    pressed: bool,
+    /// Last state read and its time, accepted once stable
+    raw: (bool, u32),
+}
+
+impl GestureDetector {
+    pub const fn new(config: GestureConfig) -> Self {
+        Self {
+            config,
+            state: State::Idle,
+            pressed: false,
+            raw: (false, 0),
+        }
+    }
+
+    /// Returns true if the button is released and no gesture is pending
+    pub fn is_idle(&self) -> bool {
+        self.state == State::Idle && !self.raw.0
+    }
+
+    /// Updates the detector at `now_ms`, `edge` being the new state of the button if it changed
+    pub fn update(&mut self, now_ms: u32, edge: Option<bool>) -> Option<Gesture> {
+        // The pending state is accepted at the time it was read, if it lasted until now
+        let (raw, since) = self.raw;
+        let gesture =
+            if raw != self.pressed && now_ms.wrapping_sub(since) >= self.config.debounce_ms {
+                self.pressed = raw;
+                self.step(since, Some(raw))
+            } else {
+                None
+            };
+        if let Some(state) = edge.filter(|&state| state != raw) {
+            self.raw = (state, now_ms);
+        }
+        gesture.or_else(|| self.step(now_ms, None))
+    }
+
+    /// Advances the state machine with an accepted edge or the passing of time
+    fn step(&mut self, now_ms: u32, edge: Option<bool>) -> Option<Gesture> {
+        let (state, gesture) = match (self.state, edge) {
+            (State::Idle, Some(true)) => (
+                State::Pressed {
+                    since: now_ms,
+                    second: false,
+                },
+                None,
+            ),
+            (State::Pressed { second: true, .. }, Some(false)) => {
+                (State::Idle, Some(Gesture::Double))
+            }
+            (State::Pressed { .. }, Some(false)) => (State::Released { at: now_ms }, None),
+            (State::Pressed { since, .. }, _)
+                if now_ms.wrapping_sub(since) >= self.config.long_press_ms =>
+            {
+                (State::Held, Some(Gesture::Long))
+            }
+            (State::Released { .. }, Some(true)) => (
+                State::Pressed {
+                    since: now_ms,
+                    second: true,
+                },
+                None,
+            ),
+            (State::Released { at }, _)
+                if now_ms.wrapping_sub(at) > self.config.double_press_ms =>
+            {
+                (State::Idle, Some(Gesture::Short))
+            }
+            (State::Held, Some(false)) => (State::Idle, None),
+            (state, _) => (state, None),
+        };
+        self.state = state;
+        gesture
+    }
+}
@@ -0,0 +1,163 @@
+//! Health of the link between the halves.
+//!
+//! Every half sends a [`Message::Heartbeat`](crate::link::Message::Heartbeat) periodically, so
+//! the other half knows the link is working even when no key is pressed. The [`LinkMonitor`]
+//! degrades the link when frames stop arriving and drops it after a longer silence. The halves
+//! keep the keys pressed on each side in a [`KeySet`], to release the remote keys when the link
+//! drops. The heartbeat carries the keys pressed on the sender half, so the other half
+//! reconciles the remote keys with it, key events lost on the way included.
+
+use crate::position::KeyPosition;
+
+/// State of the link
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum LinkState {
+    /// Frames arrive regularly
+    Up,
+    /// Frames are late, the remote keys are kept
+    Degraded,
+    /// No frame for too long, the remote keys are released
+    Down,
+}
+
+/// Silences after which the link changes state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HealthConfig {
+    /// Silence after which the link is degraded
+    pub degraded_ms: u32,
+    /// Silence after which the link is down
+    pub down_ms: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            degraded_ms: 300,
+            down_ms: 1000,
+        }
+    }
+}
+
+/// Tracks the [`LinkState`] from the time of the received frames
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinkMonitor {
+    config: HealthConfig,
+    state: LinkState,
+    last_frame: u32,
+}
+
+impl LinkMonitor {
+    /// Monitor of a link that is down until the first frame
+    pub const fn new(config: HealthConfig) -> Self {
+        Self {
+            config,
+            state: LinkState::Down,
+            last_frame: 0,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    /// Records a frame received at `now_ms`, returns true if the link was down and the halves
+    /// must re-sync
+    pub fn received(&mut self, now_ms: u32) -> bool {
+        self.last_frame = now_ms;
+        let was_down = self.state == LinkState::Down;
+        self.set(LinkState::Up);
+        was_down
+    }
+
+    /// Checks the silence at `now_ms`, returns the new state if it changed
+    pub fn update(&mut self, now_ms: u32) -> Option<LinkState> {
+        let silence = now_ms.wrapping_sub(self.last_frame);
+        match self.state {
+            LinkState::Down => None,
+            _ if silence >= self.config.down_ms => self.set(LinkState::Down),
+            _ if silence >= self.config.degraded_ms => self.set(LinkState::Degraded),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, state: LinkState) -> Option<LinkState> {
+        if state == self.state {
+            return None;
+        }
+        defmt::debug!("Link {} -> {}", self.state, state);
+        self.state = state;
+        Some(state)
+    }
+}
+
+/// Set of pressed keys in a grid of `R` rows and `C` columns, at most 64 keys
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeySet<const R: usize, const C: usize> {
+    pressed: u64,
+}
+
+impl<const R: usize, const C: usize> KeySet<R, C> {
+    pub const fn new() -> Self {
+        assert!(R * C <= 64);
+        Self { pressed: 0 }
+    }
+
+    /// Set of the keys of the bits returned by [`KeySet::bits`], the bits outside the grid are
+    /// dropped
+    pub const fn from_bits(bits: u64) -> Self {
+        let mask = if R * C == 64 {
+            u64::MAX
+        } else {
+            (1 << (R * C)) - 1
+        };
+        Self {
+            pressed: bits & mask,
+        }
+    }
+
+    /// Bit `row * C + col` is set for every pressed key
+    pub const fn bits(&self) -> u64 {
+        self.pressed
+    }
+
+    /// Records the state of a key, returns false if it did not change or the key is outside
+    /// the grid
+    pub fn set(&mut self, position: KeyPosition, pressed: bool) -> bool {
+        let (row, col) = (position.row.index(), position.col.index());
+        if row >= R || col >= C {
+            return false;
+        }
+        let before = self.pressed;
+        let bit = 1 << (row * C + col);
+        if pressed {
+            self.pressed |= bit;
+        } else {
+            self.pressed &= !bit;
+        }
+        self.pressed != before
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pressed == 0
+    }
+
+    pub fn contains(&self, position: KeyPosition) -> bool {
+        let (row, col) = (position.row.index(), position.col.index());
+        row < R && col < C && self.pressed & (1 << (row * C + col)) != 0
+    }
+
+    /// Pressed keys, row by row
+    pub fn iter(&self) -> impl Iterator<Item = KeyPosition> {
+        let pressed = self.pressed;
+        (0..R * C)
+            .filter(move |key| pressed & (1 << key) != 0)
+            .map(|key| KeyPosition::new((key / C) as u8, (key % C) as u8))
+    }
+
+    /// Empties the set, returns the keys that were pressed
+    pub fn clear(&mut self) -> impl Iterator<Item = KeyPosition> {
+        let keys = self.iter();
+        self.pressed = 0;
+        keys
+    }
+}
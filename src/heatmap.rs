@@ -0,0 +1,185 @@
+//! Key press counters per layer, to optimise the layout from typing data.
+//!
+//! Counting is opt-in: the [`Heatmap`] counts the presses of every key on the active layer only
+//! once enabled by the host. The firmware counts on the half connected to the host, which sees
+//! the keys of both halves, and saves the counters periodically in a flash
+//! [`Store`](crate::storage::Store) of their own. The periodic saves never erase the store, which
+//! takes seconds for its large sector: once it is full, the counters are saved again when the
+//! host asks for it with [`ID_SAVE_HEATMAP`].
+//!
+//! The payload of a heatmap record, also read by host tools, is [`Heatmap::encode`]: a byte set
+//! to 1 when counting is enabled, followed by the press counts (u32, big endian), layer by layer
+//! and row by row.
+//!
+//! Host tools read and control the counters with requests shaped like the ones of the
+//! [protocol](crate::protocol): the response echoes the command id, then carries a
+//! [`Status`] byte and the payload. Multi-byte values are big endian.
+//!
+//! | Command                    | Arguments       | Payload                                 |
+//! |----------------------------|-----------------|-----------------------------------------|
+//! | [`ID_GET_HEATMAP_INFO`]    |                 | enabled, layers, rows, columns          |
+//! | [`ID_SET_HEATMAP_ENABLED`] | enabled (0, 1)  |                                         |
+//! | [`ID_RESET_HEATMAP`]       |                 |                                         |
+//! | [`ID_GET_PRESSES`]         | layer, row, col | presses (u32) of the next keys of `row` |
+//! | [`ID_SAVE_HEATMAP`]        |                 |                                         |
+//!
+//! [`ID_GET_PRESSES`] answers with the counts of the keys from `col` to the end of the row,
+//! [`PRESSES_PER_REPORT`] at most.
+
+use crate::{
+    position::KeyPosition,
+    protocol::{Effect, Status, REPORT_SIZE},
+};
+
+pub const ID_GET_HEATMAP_INFO: u8 = 0xea;
+pub const ID_SET_HEATMAP_ENABLED: u8 = 0xeb;
+pub const ID_RESET_HEATMAP: u8 = 0xec;
+pub const ID_GET_PRESSES: u8 = 0xed;
+pub const ID_SAVE_HEATMAP: u8 = 0xef;
+
+/// Largest number of counts in a response to [`ID_GET_PRESSES`]
+pub const PRESSES_PER_REPORT: usize = (REPORT_SIZE - 2) / 4;
+
+/// Returns true if `id` is a heatmap command id rather than a native protocol one
+pub fn is_heatmap_command(id: u8) -> bool {
+    matches!(id, ID_GET_HEATMAP_INFO..=ID_GET_PRESSES | ID_SAVE_HEATMAP)
+}
+
+/// Press counts of the keys of a keyboard of `L` layers, `R` rows and `C` columns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heatmap<const L: usize, const R: usize, const C: usize> {
+    enabled: bool,
+    presses: [[[u32; C]; R]; L],
+    /// Changed since the last save
+    dirty: bool,
+}
+
+impl<const L: usize, const R: usize, const C: usize> Default for Heatmap<L, R, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const L: usize, const R: usize, const C: usize> Heatmap<L, R, C> {
+    /// Size of the encoded heatmap
+    pub const SIZE: usize = 1 + L * R * C * 4;
+
+    /// Disabled heatmap without presses
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            presses: [[[0; C]; R]; L],
+            dirty: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Starts or stops counting, the counts are kept
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.dirty |= self.enabled != enabled;
+        self.enabled = enabled;
+    }
+
+    /// Clears the counts
+    pub fn reset(&mut self) {
+        self.presses = [[[0; C]; R]; L];
+        self.dirty = true;
+    }
+
+    /// Counts a press of the key at `position` on `layer`, if enabled
+    pub fn record(&mut self, layer: usize, position: KeyPosition) {
+        if !self.enabled {
+            return;
+        }
+        if let Some(presses) = self
+            .presses
+            .get_mut(layer)
+            .and_then(|layer| layer.get_mut(position.row.index()))
+            .and_then(|row| row.get_mut(position.col.index()))
+        {
+            *presses = presses.saturating_add(1);
+            self.dirty = true;
+        }
+    }
+
+    /// Presses of the key at `position` on `layer`
+    pub fn presses(&self, layer: usize, position: KeyPosition) -> Option<u32> {
+        self.presses
+            .get(layer)
+            .and_then(|layer| layer.get(position.row.index()))
+            .and_then(|row| row.get(position.col.index()))
+            .copied()
+    }
+
+    /// Returns true if the heatmap changed since the previous call
+    pub fn take_dirty(&mut self) -> bool {
+        core::mem::replace(&mut self.dirty, false)
+    }
+
+    /// Encodes the heatmap in the first [`Self::SIZE`] bytes of `buf` and returns them
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> &'b [u8] {
+        let (enabled, presses) = buf[..Self::SIZE].split_at_mut(1);
+        enabled[0] = self.enabled as u8;
+        let counts = self.presses.iter().flatten().flatten();
+        for (bytes, count) in presses.chunks_exact_mut(4).zip(counts) {
+            bytes.copy_from_slice(&count.to_be_bytes());
+        }
+        &buf[..Self::SIZE]
+    }
+
+    /// Decodes an encoded heatmap, returns `None` if it does not match the keyboard size
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::SIZE || bytes[0] > 1 {
+            return None;
+        }
+        let mut heatmap = Self::new();
+        heatmap.enabled = bytes[0] == 1;
+        let counts = heatmap.presses.iter_mut().flatten().flatten();
+        for (count, bytes) in counts.zip(bytes[1..].chunks_exact(4)) {
+            *count = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        Some(heatmap)
+    }
+
+    /// Answers a heatmap request and returns the response with the work left to the firmware
+    pub fn handle_request(&mut self, request: &[u8; REPORT_SIZE]) -> ([u8; REPORT_SIZE], Effect) {
+        let mut response = [0; REPORT_SIZE];
+        response[0] = request[0];
+        let result = match request[0] {
+            ID_GET_HEATMAP_INFO => {
+                response[2..6].copy_from_slice(&[self.enabled as u8, L as u8, R as u8, C as u8]);
+                Ok(Effect::None)
+            }
+            ID_SET_HEATMAP_ENABLED if request[1] <= 1 => {
+                self.set_enabled(request[1] == 1);
+                Ok(Effect::HeatmapChanged)
+            }
+            ID_RESET_HEATMAP => {
+                self.reset();
+                Ok(Effect::HeatmapChanged)
+            }
+            ID_GET_PRESSES => {
+                let (layer, row, col) = (usize::from(request[1]), request[2], request[3]);
+                if self.presses(layer, KeyPosition::new(row, col)).is_none() {
+                    Err(Status::InvalidArgument)
+                } else {
+                    let cols = col..(C as u8).min(col + PRESSES_PER_REPORT as u8);
+                    for (bytes, col) in response[2..].chunks_exact_mut(4).zip(cols) {
+                        let presses = self.presses(layer, KeyPosition::new(row, col));
+                        bytes.copy_from_slice(&presses.unwrap_or(0).to_be_bytes());
+                    }
+                    Ok(Effect::None)
+                }
+            }
+            // Saved if changed, erasing the store when full
+            ID_SAVE_HEATMAP => Ok(Effect::HeatmapChanged),
+            ID_SET_HEATMAP_ENABLED => Err(Status::InvalidArgument),
+            _ => Err(Status::UnknownCommand),
+        };
+        response[1] = result.err().unwrap_or(Status::Ok) as u8;
+        (response, result.unwrap_or(Effect::None))
+    }
+}
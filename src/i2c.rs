@@ -0,0 +1,380 @@
+//! I²C transport, the master half polls the key matrix of the slave half.
+//!
+//! The slave exposes these registers:
+//!
+//! | Register | Access | Content                                                           |
+//! |----------|--------|-------------------------------------------------------------------|
+//! | `0x00`   | read   | side of the slave, as [`Side::to_byte`], `0xff` until known       |
+//! | `0x01`   | read   | pressed keys, one bit per key row by row, least significant first |
+//! | `0x09`   | read   | steps of each encoder, signed byte, clockwise positive            |
+//! | `0x0b`   | read   | pointer movement, signed bytes x then y                           |
+//! | `0x80`   | write  | link frame for the slave                                          |
+//!
+//! A transaction starts with the register written by the master, reads continue with the
+//! following registers. The matrix has one bit per key of the whole keyboard, `C` being the
+//! number of columns of both halves, so the slave sets the bits of its keys at the shifted
+//! columns of their [`Message::Event`]s. The encoder registers count the steps of the encoders
+//! numbered below [`MAX_ENCODERS`] and the motion registers sum the pointer movements, both
+//! since they were last read, reading them clears them.
+//!
+//! The slave cannot start a transfer, so it only reports its side, its keys, its encoders and
+//! its joystick: its [`Message::Hello`] sets the side register, its key events set the matrix,
+//! its [`Message::Encoder`]s count the steps, its [`Message::Motion`]s add up and its other
+//! messages are dropped. Events are stamped with the time of the poll on the clock of the master.
+//! Every successful poll also yields a [`Message::Heartbeat`] with the keys reported so far, the
+//! slave answering shows that the link is up.
+//!
+//! The transfers of the master block, so the [`I2cBus`] is kept apart from the [`I2cMaster`]:
+//! the master queues its messages for the mailbox and handles the registers read, while the
+//! firmware does the transfers without holding the master. [`I2cLink`] is the transport of a
+//! half that is either of them, picked at startup.
+
+use crate::{
+    encoder::Direction,
+    joystick::Motion,
+    link::{Decoder, Message, MAX_FRAME},
+    position::KeyPosition,
+    side::Side,
+    transport::{QueueFull, Transport, INBOX_SIZE},
+};
+use core::convert::Infallible;
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use heapless::{Deque, Vec};
+
+/// Default 7-bit address of the slave
+pub const DEFAULT_ADDRESS: u8 = 0x42;
+
+pub const REG_SIDE: u8 = 0x00;
+pub const REG_MATRIX: u8 = 0x01;
+pub const REG_ENCODERS: u8 = 0x09;
+pub const REG_MOTION: u8 = 0x0b;
+pub const REG_MAILBOX: u8 = 0x80;
+
+/// Largest number of keys of the matrix
+pub const MAX_KEYS: usize = 64;
+
+/// Largest number of encoders of the keyboard
+pub const MAX_ENCODERS: usize = 2;
+
+const MATRIX_BYTES: usize = MAX_KEYS / 8;
+
+const REGISTERS: usize = 1 + MATRIX_BYTES + MAX_ENCODERS + 2;
+
+/// Side, matrix, encoder and motion registers
+pub type Registers = [u8; REGISTERS];
+
+/// Messages of the master waiting for the next transfer
+const OUTBOX_SIZE: usize = 8;
+
+/// Mailbox register followed by a frame
+const MAILBOX_WRITE: usize = 1 + MAX_FRAME;
+
+fn is_pressed(matrix: &[u8], key: usize) -> bool {
+    matrix[key / 8] & (1 << (key % 8)) != 0
+}
+
+fn set_pressed(matrix: &mut [u8], key: usize, pressed: bool) {
+    if pressed {
+        matrix[key / 8] |= 1 << (key % 8);
+    } else {
+        matrix[key / 8] &= !(1 << (key % 8));
+    }
+}
+
+/// Bus of the master to the slave at `address`
+pub struct I2cBus<I2C> {
+    i2c: I2C,
+    address: u8,
+}
+
+impl<I2C, E> I2cBus<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    pub fn new(i2c: I2C, address: u8) -> Self {
+        Self { i2c, address }
+    }
+
+    /// Reads the registers of the slave
+    pub fn read(&mut self) -> Result<Registers, E> {
+        let mut registers = [0; REGISTERS];
+        self.i2c
+            .write_read(self.address, &[REG_SIDE], &mut registers)?;
+        Ok(registers)
+    }
+
+    /// Writes the message to the mailbox of the slave
+    pub fn write(&mut self, message: &Message) -> Result<(), E> {
+        let mut bytes: Vec<u8, MAILBOX_WRITE> = Vec::new();
+        bytes.push(REG_MAILBOX).ok();
+        bytes.extend_from_slice(&message.encode()).ok();
+        self.i2c.write(self.address, &bytes)
+    }
+}
+
+/// Half reading the matrix of the other one, with a grid of `R` rows and `C` columns
+pub struct I2cMaster<const R: usize, const C: usize> {
+    side: Option<Side>,
+    matrix: [u8; MATRIX_BYTES],
+    /// Steps of each encoder not reported yet, clockwise positive
+    steps: [i8; MAX_ENCODERS],
+    /// Movement not reported yet
+    motion: Motion,
+    inbox: Deque<Message, INBOX_SIZE>,
+    outbox: Deque<Message, OUTBOX_SIZE>,
+}
+
+impl<const R: usize, const C: usize> I2cMaster<R, C> {
+    pub fn new() -> Self {
+        assert!(R * C <= MAX_KEYS);
+        Self {
+            side: None,
+            matrix: [0; MATRIX_BYTES],
+            steps: [0; MAX_ENCODERS],
+            motion: Motion::default(),
+            inbox: Deque::new(),
+            outbox: Deque::new(),
+        }
+    }
+
+    /// Handles the `registers` of the slave read at `now_us`.
+    ///
+    /// Queues a [`Message::Hello`] when the side of the slave changes, a [`Message::Event`]
+    /// for every key that changed state, a [`Message::Encoder`] for every step of the encoders,
+    /// a [`Message::Motion`] when the pointer moved and a [`Message::Heartbeat`]. Changes that do
+    /// not fit in the inbox are reported by a later poll.
+    pub fn update(&mut self, registers: &Registers, now_us: u32) {
+        if let Some(side) = Side::from_byte(registers[usize::from(REG_SIDE)]) {
+            if self.side != Some(side) && self.inbox.push_back(Message::Hello { side }).is_ok() {
+                self.side = Some(side);
+            }
+        }
+
+        let matrix = &registers[usize::from(REG_MATRIX)..];
+        for key in 0..R * C {
+            let pressed = is_pressed(matrix, key);
+            if pressed == is_pressed(&self.matrix, key) {
+                continue;
+            }
+            let event = Message::Event {
+                position: KeyPosition::new((key / C) as u8, (key % C) as u8),
+                pressed,
+                time_us: now_us,
+            };
+            if self.inbox.push_back(event).is_err() {
+                break;
+            }
+            set_pressed(&mut self.matrix, key, pressed);
+        }
+
+        let steps = &registers[usize::from(REG_ENCODERS)..];
+        for (index, (pending, &steps)) in self.steps.iter_mut().zip(steps).enumerate() {
+            *pending = pending.saturating_add(steps as i8);
+            while *pending != 0 {
+                let direction = if *pending > 0 {
+                    Direction::Clockwise
+                } else {
+                    Direction::CounterClockwise
+                };
+                let index = index as u8;
+                let step = Message::Encoder { index, direction };
+                if self.inbox.push_back(step).is_err() {
+                    break;
+                }
+                *pending -= pending.signum();
+            }
+        }
+
+        let motion = &registers[usize::from(REG_MOTION)..];
+        self.motion = self.motion.merge(Motion {
+            dx: motion[0] as i8,
+            dy: motion[1] as i8,
+        });
+        if !self.motion.is_zero() && self.inbox.push_back(Message::Motion(self.motion)).is_ok() {
+            self.motion = Motion::default();
+        }
+        let pressed = u64::from_le_bytes(self.matrix);
+        self.inbox.push_back(Message::Heartbeat { pressed }).ok();
+    }
+
+    /// Takes the oldest queued message, to write to the mailbox of the slave
+    pub fn next_write(&mut self) -> Option<Message> {
+        self.outbox.pop_front()
+    }
+}
+
+impl<const R: usize, const C: usize> Default for I2cMaster<R, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const R: usize, const C: usize> Transport for I2cMaster<R, C> {
+    type Error = QueueFull;
+
+    /// Queues the message for the mailbox of the slave, see [`I2cMaster::next_write`]
+    fn send(&mut self, message: &Message) -> Result<(), QueueFull> {
+        self.outbox.push_back(*message).map_err(|_| QueueFull)
+    }
+
+    fn receive(&mut self) -> Option<Message> {
+        self.inbox.pop_front()
+    }
+}
+
+/// Half exposing its matrix to the other one, with a grid of `R` rows and `C` columns.
+///
+/// The I²C interrupt handler reports the bus events with [`I2cSlave::start`],
+/// [`I2cSlave::write`] and [`I2cSlave::read`].
+#[derive(Clone, Debug)]
+pub struct I2cSlave<const R: usize, const C: usize> {
+    registers: Registers,
+    /// Register accessed by the next byte
+    pointer: u8,
+    /// The next written byte selects the register
+    selecting: bool,
+    decoder: Decoder,
+    inbox: Deque<Message, INBOX_SIZE>,
+}
+
+impl<const R: usize, const C: usize> I2cSlave<R, C> {
+    pub fn new() -> Self {
+        assert!(R * C <= MAX_KEYS);
+        let mut registers = [0; REGISTERS];
+        registers[usize::from(REG_SIDE)] = 0xff;
+        Self {
+            registers,
+            pointer: REG_SIDE,
+            selecting: false,
+            decoder: Decoder::new(),
+            inbox: Deque::new(),
+        }
+    }
+
+    /// The master addressed the slave, for a write or a read
+    pub fn start(&mut self, write: bool) {
+        self.selecting = write;
+    }
+
+    /// The master wrote a byte
+    pub fn write(&mut self, byte: u8) {
+        if self.selecting {
+            self.selecting = false;
+            self.pointer = byte;
+        } else if self.pointer == REG_MAILBOX {
+            if let Some(message) = self.decoder.push(byte) {
+                self.inbox.push_back(message).ok();
+            }
+        }
+    }
+
+    /// Returns the byte read by the master, clearing the encoder and motion registers
+    pub fn read(&mut self) -> u8 {
+        let pointer = usize::from(self.pointer);
+        let byte = self.registers.get(pointer).copied().unwrap_or(0xff);
+        if pointer >= usize::from(REG_ENCODERS) {
+            if let Some(register) = self.registers.get_mut(pointer) {
+                *register = 0;
+            }
+        }
+        self.pointer = self.pointer.saturating_add(1);
+        byte
+    }
+}
+
+impl<const R: usize, const C: usize> Default for I2cSlave<R, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const R: usize, const C: usize> Transport for I2cSlave<R, C> {
+    type Error = Infallible;
+
+    /// Updates the registers read by the master
+    fn send(&mut self, message: &Message) -> Result<(), Infallible> {
+        match *message {
+            Message::Hello { side } => self.registers[usize::from(REG_SIDE)] = side.to_byte(),
+            Message::Event {
+                position, pressed, ..
+            } if position.row.index() < R && position.col.index() < C => {
+                let key = position.row.index() * C + position.col.index();
+                set_pressed(&mut self.registers[usize::from(REG_MATRIX)..], key, pressed);
+            }
+            Message::Encoder { index, direction } if usize::from(index) < MAX_ENCODERS => {
+                let steps = &mut self.registers[usize::from(REG_ENCODERS) + usize::from(index)];
+                let step = match direction {
+                    Direction::Clockwise => 1,
+                    Direction::CounterClockwise => -1,
+                };
+                *steps = (*steps as i8).saturating_add(step) as u8;
+            }
+            Message::Motion(motion) => {
+                let registers = &mut self.registers[usize::from(REG_MOTION)..];
+                let sum = motion.merge(Motion {
+                    dx: registers[0] as i8,
+                    dy: registers[1] as i8,
+                });
+                registers.copy_from_slice(&[sum.dx as u8, sum.dy as u8]);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Option<Message> {
+        self.inbox.pop_front()
+    }
+}
+
+/// Transport of a half that is the master or the slave of the I²C link
+pub enum I2cLink<const R: usize, const C: usize> {
+    Master(I2cMaster<R, C>),
+    Slave(I2cSlave<R, C>),
+}
+
+impl<const R: usize, const C: usize> I2cLink<R, C> {
+    /// Handles the registers of the slave read at `now_us`, the slave has nothing to do
+    pub fn update(&mut self, registers: &Registers, now_us: u32) {
+        if let I2cLink::Master(master) = self {
+            master.update(registers, now_us);
+        }
+    }
+
+    /// Takes the next message of the master for the slave, the slave writes none
+    pub fn next_write(&mut self) -> Option<Message> {
+        match self {
+            I2cLink::Master(master) => master.next_write(),
+            I2cLink::Slave(_) => None,
+        }
+    }
+
+    /// The slave transport, to report the bus events to
+    pub fn slave_mut(&mut self) -> Option<&mut I2cSlave<R, C>> {
+        match self {
+            I2cLink::Master(_) => None,
+            I2cLink::Slave(slave) => Some(slave),
+        }
+    }
+}
+
+impl<const R: usize, const C: usize> Transport for I2cLink<R, C> {
+    type Error = QueueFull;
+
+    fn send(&mut self, message: &Message) -> Result<(), QueueFull> {
+        match self {
+            I2cLink::Master(master) => master.send(message),
+            I2cLink::Slave(slave) => match slave.send(message) {
+                Ok(()) => Ok(()),
+                Err(never) => match never {},
+            },
+        }
+    }
+
+    fn receive(&mut self) -> Option<Message> {
+        match self {
+            I2cLink::Master(master) => master.receive(),
+            I2cLink::Slave(slave) => slave.receive(),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Analog thumb joystick moving the mouse pointer.
+//!
+//! The firmware samples both axes of the joystick with the ADC and hands them to a [`Joystick`].
+//! The first [`CALIBRATION_SAMPLES`] samples, taken with the stick at rest, set the center of
+//! each axis. The range of an axis starts at [`JoystickConfig::range`] on each side of the center
+//! and widens when the stick goes further.
+//!
+//! Deflections within the dead zone are ignored. Beyond it, the speed follows a blend of a linear
+//! and a quadratic curve of the deflection, so that the pointer is precise near the center and
+//! fast at the edge. Fractions of a count are carried over to the next samples.
+//!
+//! The movement is reported in the [`MOUSE_DESCRIPTOR`] reports of a boot mouse by the half
+//! connected to the host.
+
+/// Samples averaged to find the center of the axes
+pub const CALIBRATION_SAMPLES: u16 = 16;
+
+/// Deflection of a fully deflected axis
+const FULL: i32 = 1000;
+
+/// Size of the reports of [`MOUSE_DESCRIPTOR`]
+pub const MOUSE_REPORT_SIZE: usize = 3;
+
+/// Report descriptor of a boot mouse: three buttons and 8-bit relative X and Y movements.
+#[rustfmt::skip]
+pub const MOUSE_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Button)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Variable, Absolute)
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Constant)
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x06, //     Input (Data, Variable, Relative)
+    0xc0,       //   End Collection
+    0xc0,       // End Collection
+];
+
+/// Response of the joystick
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JoystickConfig {
+    /// Raw distance from the center ignored on each axis
+    pub dead_zone: u16,
+    /// Raw distance from the center of a full deflection, at least
+    pub range: u16,
+    /// Movement of a full deflection, in counts per sample
+    pub max_speed: u8,
+    /// Weight of the quadratic curve against the linear one, from 0 to 1000
+    pub acceleration: u16,
+    pub invert_x: bool,
+    pub invert_y: bool,
+}
+
+impl Default for JoystickConfig {
+    /// Joystick sampled by a 12-bit ADC every 10 ms
+    fn default() -> Self {
+        Self {
+            dead_zone: 120,
+            range: 1_800,
+            max_speed: 20,
+            acceleration: 700,
+            invert_x: false,
+            invert_y: false,
+        }
+    }
+}
+
+/// Pointer movement, in counts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub struct Motion {
+    pub dx: i8,
+    pub dy: i8,
+}
+
+impl Motion {
+    pub fn is_zero(&self) -> bool {
+        self.dx == 0 && self.dy == 0
+    }
+
+    /// Sum of two movements, saturating
+    pub fn merge(self, other: Motion) -> Motion {
+        Motion {
+            dx: self.dx.saturating_add(other.dx),
+            dy: self.dy.saturating_add(other.dy),
+        }
+    }
+
+    /// Encodes the report of [`MOUSE_DESCRIPTOR`], without buttons pressed
+    pub fn report(&self) -> [u8; MOUSE_REPORT_SIZE] {
+        // -128 is out of the logical range
+        let clamp = |delta: i8| delta.max(-127) as u8;
+        [0, clamp(self.dx), clamp(self.dy)]
+    }
+}
+
+/// Calibration of an axis
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Axis {
+    center: u16,
+    min: u16,
+    max: u16,
+}
+
+impl Axis {
+    fn new(center: u16, range: u16) -> Self {
+        Self {
+            center,
+            min: center.saturating_sub(range),
+            max: center.saturating_add(range),
+        }
+    }
+
+    /// Deflection beyond the dead zone, from -[`FULL`] to [`FULL`]
+    fn deflection(&mut self, raw: u16, dead_zone: u16) -> i32 {
+        self.min = self.min.min(raw);
+        self.max = self.max.max(raw);
+        let offset = i32::from(raw) - i32::from(self.center);
+        let span = if offset > 0 {
+            self.max - self.center
+        } else {
+            self.center - self.min
+        };
+        let span = i32::from(span) - i32::from(dead_zone);
+        let magnitude = offset.abs() - i32::from(dead_zone);
+        if magnitude <= 0 || span <= 0 {
+            return 0;
+        }
+        offset.signum() * (magnitude * FULL / span).min(FULL)
+    }
+}
+
+/// Two-axis joystick turning samples into pointer movements
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Joystick {
+    config: JoystickConfig,
+    /// Sums of the X and Y samples taken at rest and their number
+    calibration: (u32, u32, u16),
+    axes: Option<[Axis; 2]>,
+    /// Movement not reported yet on each axis, in thousandths of a count
+    remainders: [i32; 2],
+}
+
+impl Joystick {
+    /// Joystick to calibrate
+    pub const fn new(config: JoystickConfig) -> Self {
+        Self {
+            config,
+            calibration: (0, 0, 0),
+            axes: None,
+            remainders: [0; 2],
+        }
+    }
+
+    pub fn is_calibrated(&self) -> bool {
+        self.axes.is_some()
+    }
+
+    /// Handles the raw samples of both axes, returns the movement they cause
+    pub fn update(&mut self, x: u16, y: u16) -> Option<Motion> {
+        let config = self.config;
+        let axes = match self.axes.as_mut() {
+            Some(axes) => axes,
+            None => {
+                let (sum_x, sum_y, count) = &mut self.calibration;
+                *sum_x += u32::from(x);
+                *sum_y += u32::from(y);
+                *count += 1;
+                if *count == CALIBRATION_SAMPLES {
+                    let center = |sum: u32| (sum / u32::from(CALIBRATION_SAMPLES)) as u16;
+                    let (x, y) = (center(*sum_x), center(*sum_y));
+                    defmt::debug!("Joystick centered at {}, {}", x, y);
+                    self.axes = Some([Axis::new(x, config.range), Axis::new(y, config.range)]);
+                }
+                return None;
+            }
+        };
+
+        let mut deltas = [0; 2];
+        let samples = [(x, config.invert_x), (y, config.invert_y)];
+        for (((axis, (raw, invert)), remainder), delta) in axes
+            .iter_mut()
+            .zip(samples)
+            .zip(&mut self.remainders)
+            .zip(&mut deltas)
+        {
+            let deflection = axis.deflection(raw, config.dead_zone);
+            if deflection == 0 {
+                // No drift from the fractions left when the stick comes back
+                *remainder = 0;
+                continue;
+            }
+            let magnitude = deflection.abs();
+            let accel = i32::from(config.acceleration.min(FULL as u16));
+            let curve = (magnitude * (FULL - accel) + magnitude * magnitude / FULL * accel) / FULL;
+            let speed = curve * i32::from(config.max_speed);
+            let direction = if invert { -deflection } else { deflection }.signum();
+            *remainder += direction * speed;
+            let counts = (*remainder / FULL).clamp(-127, 127);
+            *remainder -= counts * FULL;
+            *delta = counts as i8;
+        }
+
+        let motion = Motion {
+            dx: deltas[0],
+            dy: deltas[1],
+        };
+        Some(motion).filter(|motion| !motion.is_zero())
+    }
+}
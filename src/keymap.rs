@@ -0,0 +1,271 @@
+//! Runtime keymap, editable from the host and convertible into a `GridState`.
+
+use crate::position::KeyEvent;
+use core::convert::TryFrom;
+use keyboard_io::{
+    buttons::{shortcuts::bs, Button, ButtonAction, GridState},
+    codes::KeyboardCode,
+};
+
+/// Keycode of [`Action::No`]
+pub const KC_NO: u16 = 0x0000;
+/// Keycode of [`Action::Transparent`]
+pub const KC_TRANSPARENT: u16 = 0x0001;
+/// Base keycode of [`Action::MomentaryLayer`], the layer is stored in the low bits
+pub const KC_MOMENTARY_LAYER: u16 = 0x5220;
+/// Keycode of [`Action::Bootloader`]
+pub const KC_BOOTLOADER: u16 = 0x7c00;
+/// Keycode of [`Action::Reset`]
+pub const KC_REBOOT: u16 = 0x7c01;
+
+/// Action bound to a key on a layer.
+///
+/// Actions are exchanged with the host as 16 bit keycodes, using the same numbering as QMK so
+/// that existing host tools can display them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Action {
+    /// Does nothing, also on lower layers
+    No,
+    /// Uses the action of the base layer
+    Transparent,
+    /// Sends a HID keyboard usage
+    Key(u8),
+    /// Activates a layer while held
+    MomentaryLayer(u8),
+    /// Resets into the DFU bootloader when pressed
+    Bootloader,
+    /// Resets the MCU when pressed
+    Reset,
+}
+
+impl Action {
+    /// Encodes the action as a 16 bit keycode
+    pub const fn keycode(self) -> u16 {
+        match self {
+            Action::No => KC_NO,
+            Action::Transparent => KC_TRANSPARENT,
+            Action::Key(code) => code as u16,
+            Action::MomentaryLayer(layer) => KC_MOMENTARY_LAYER | (layer as u16 & 0x1f),
+            Action::Bootloader => KC_BOOTLOADER,
+            Action::Reset => KC_REBOOT,
+        }
+    }
+
+    /// Decodes a 16 bit keycode, returns `None` for unsupported keycodes
+    pub const fn from_keycode(keycode: u16) -> Option<Self> {
+        match keycode {
+            KC_NO => Some(Action::No),
+            KC_TRANSPARENT => Some(Action::Transparent),
+            0x0004..=0x00ff => Some(Action::Key(keycode as u8)),
+            KC_BOOTLOADER => Some(Action::Bootloader),
+            KC_REBOOT => Some(Action::Reset),
+            _ if keycode & !0x1f == KC_MOMENTARY_LAYER => {
+                Some(Action::MomentaryLayer((keycode & 0x1f) as u8))
+            }
+            _ => None,
+        }
+    }
+
+    /// Keyboard usage sent by the action, if any
+    fn keyboard_code(self) -> Option<KeyboardCode> {
+        match self {
+            Action::No => KeyboardCode::try_from(0).ok(),
+            Action::Key(code) => KeyboardCode::try_from(code).ok(),
+            Action::Transparent
+            | Action::MomentaryLayer(_)
+            | Action::Bootloader
+            | Action::Reset => None,
+        }
+    }
+}
+
+/// Errors returned when editing a [`Keymap`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum KeymapError {
+    /// Layer, row or column outside the keymap
+    OutOfRange,
+    /// The action cannot be used at the requested position
+    Unsupported,
+}
+
+/// Actions of a `R`×`C` grid on `L` layers, layer 0 being the base layer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keymap<const R: usize, const C: usize, const L: usize> {
+    layers: [[[Action; C]; R]; L],
+}
+
+impl<const R: usize, const C: usize, const L: usize> Keymap<R, C, L> {
+    /// Creates a keymap from its layers
+    pub const fn new(layers: [[[Action; C]; R]; L]) -> Self {
+        Self { layers }
+    }
+
+    /// Number of layers, base layer included
+    pub const fn layer_count(&self) -> usize {
+        L
+    }
+
+    /// Action at the given position
+    pub fn get(&self, layer: usize, row: usize, col: usize) -> Result<Action, KeymapError> {
+        self.layers
+            .get(layer)
+            .and_then(|l| l.get(row))
+            .and_then(|r| r.get(col))
+            .copied()
+            .ok_or(KeymapError::OutOfRange)
+    }
+
+    /// Replaces the action at the given position.
+    ///
+    /// Layer switches are only supported on the base layer and must target an existing layer.
+    pub fn set(
+        &mut self,
+        layer: usize,
+        row: usize,
+        col: usize,
+        action: Action,
+    ) -> Result<(), KeymapError> {
+        match action {
+            Action::MomentaryLayer(target) if layer != 0 || target == 0 || target as usize >= L => {
+                return Err(KeymapError::Unsupported)
+            }
+            Action::Transparent if layer == 0 => return Err(KeymapError::Unsupported),
+            _ => {}
+        }
+
+        let slot = self
+            .layers
+            .get_mut(layer)
+            .and_then(|l| l.get_mut(row))
+            .and_then(|r| r.get_mut(col))
+            .ok_or(KeymapError::OutOfRange)?;
+        *slot = action;
+        Ok(())
+    }
+
+    /// Action at the given position when `layer` is active on top of the `default` layer.
+    ///
+    /// Transparent actions fall through to the default layer, then to the base layer.
+    pub fn resolve(
+        &self,
+        layer: usize,
+        default: usize,
+        row: usize,
+        col: usize,
+    ) -> Result<Action, KeymapError> {
+        for layer in [layer, default, 0] {
+            match self.get(layer, row, col)? {
+                Action::Transparent => continue,
+                action => return Ok(action),
+            }
+        }
+        Ok(Action::No)
+    }
+
+    /// Builds the button of a position, mapping layer `n` to `GridState` layer `n - 1`
+    fn button<K: From<KeyboardCode>>(&self, default: usize, row: usize, col: usize) -> Button<K> {
+        let base = self
+            .resolve(default, default, row, col)
+            .unwrap_or(Action::No);
+        let mut action = match base {
+            Action::MomentaryLayer(layer) => ButtonAction::MomentaryLayer((layer - 1).into()),
+            base => bs(K::from(
+                base.keyboard_code()
+                    .or_else(|| Action::No.keyboard_code())
+                    .unwrap(),
+            )),
+        };
+        for (layer, actions) in (0u8..).zip(self.layers[1..].iter()) {
+            if let Some(code) = actions[row][col].keyboard_code() {
+                action = action.add_layer(K::from(code), layer.into());
+            }
+        }
+        Button::new(action)
+    }
+
+    /// Builds the `GridState` used to produce the HID reports, `N` must be `L - 1`
+    pub fn grid_state<K: From<KeyboardCode>, const N: usize>(&self) -> GridState<K, R, C, N> {
+        self.grid_state_with_default(0)
+    }
+
+    /// Builds the `GridState` with `default` as base layer, falling back to layer 0 on
+    /// transparent actions
+    pub fn grid_state_with_default<K: From<KeyboardCode>, const N: usize>(
+        &self,
+        default: usize,
+    ) -> GridState<K, R, C, N> {
+        debug_assert_eq!(N + 1, L);
+        GridState::new(core::array::from_fn(|row| {
+            core::array::from_fn(|col| self.button(default, row, col))
+        }))
+    }
+}
+
+/// Tracks the default layer and the momentary layer keys held down, to know the active layer.
+///
+/// The `GridState` keeps its own layer state for the HID reports, this one is used to find the
+/// actions it does not handle, such as [`Action::Bootloader`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayerState<const L: usize> {
+    /// Number of keys held down for each layer
+    held: [u8; L],
+    /// Layer used as base layer
+    default: usize,
+}
+
+impl<const L: usize> Default for LayerState<L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const L: usize> LayerState<L> {
+    pub const fn new() -> Self {
+        Self {
+            held: [0; L],
+            default: 0,
+        }
+    }
+
+    /// Layer used as base layer
+    pub fn default_layer(&self) -> usize {
+        self.default
+    }
+
+    /// Switches the default layer to the next one, wrapping around to layer 0
+    pub fn cycle_default(&mut self) -> usize {
+        self.default = (self.default + 1) % L;
+        self.default
+    }
+
+    /// Highest layer with a key held down, the default layer if none
+    pub fn active(&self) -> usize {
+        self.held
+            .iter()
+            .rposition(|&n| n > 0)
+            .unwrap_or(self.default)
+    }
+
+    /// Records a key event and returns the action of the key on the active layer
+    pub fn update<const R: usize, const C: usize>(
+        &mut self,
+        keymap: &Keymap<R, C, L>,
+        event: KeyEvent,
+    ) -> Action {
+        let (row, col) = (event.position.row.index(), event.position.col.index());
+        let action = keymap
+            .resolve(self.active(), self.default, row, col)
+            .unwrap_or(Action::No);
+        let base = keymap.resolve(self.default, self.default, row, col);
+        if let Ok(Action::MomentaryLayer(layer)) = base {
+            if let Some(held) = self.held.get_mut(usize::from(layer)) {
+                *held = if event.pressed {
+                    held.saturating_add(1)
+                } else {
+                    held.saturating_sub(1)
+                };
+            }
+        }
+        action
+    }
+}
@@ -0,0 +1,59 @@
+//! Flash partitions defined in `memory.x`.
+
+/// Address of the first byte of flash
+const FLASH_BASE: u32 = 0x0800_0000;
+
+extern "C" {
+    static __upgrade_start: u8;
+    static __upgrade_end: u8;
+    static __heatmap_start: u8;
+    static __heatmap_end: u8;
+    static __settings_start: u8;
+    static __settings_end: u8;
+}
+
+/// Flash region, with the offset from the start of the flash used by the flash driver
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Partition {
+    pub offset: u32,
+    pub size: u32,
+}
+
+impl Partition {
+    fn from_symbols(start: *const u8, end: *const u8) -> Self {
+        Self {
+            offset: start as u32 - FLASH_BASE,
+            size: end as u32 - start as u32,
+        }
+    }
+}
+
+/// Region reserved for the settings store
+pub fn settings() -> Partition {
+    unsafe {
+        Partition::from_symbols(
+            core::ptr::addr_of!(__settings_start),
+            core::ptr::addr_of!(__settings_end),
+        )
+    }
+}
+
+/// Region reserved for the key press counters
+pub fn heatmap() -> Partition {
+    unsafe {
+        Partition::from_symbols(
+            core::ptr::addr_of!(__heatmap_start),
+            core::ptr::addr_of!(__heatmap_end),
+        )
+    }
+}
+
+/// Region reserved for staging a new application image
+pub fn upgrade() -> Partition {
+    unsafe {
+        Partition::from_symbols(
+            core::ptr::addr_of!(__upgrade_start),
+            core::ptr::addr_of!(__upgrade_end),
+        )
+    }
+}
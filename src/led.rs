@@ -0,0 +1,98 @@
+//! Status LED patterns.
+//!
+//! The LED shows the most important state among the ones enabled in the [`LedConfig`] of the
+//! half, from the highest priority:
+//!
+//! | State                | Pattern                                        |
+//! |----------------------|------------------------------------------------|
+//! | USB not enumerated   | 100 ms flash every second                      |
+//! | Inter-half link lost | 500 ms on, 500 ms off                          |
+//! | Caps Lock            | steady on                                      |
+//! | Layer `n`            | `n` flashes of 150 ms, then a pause; off for 0 |
+//!
+//! Patterns are a function of the time, so the LED task only has to sample them periodically.
+
+/// State shown by the LED
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub struct Indicators {
+    pub usb_configured: bool,
+    pub link_up: bool,
+    pub caps_lock: bool,
+    /// Active layer
+    pub layer: u8,
+}
+
+/// States shown by the LED of a half
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LedConfig {
+    pub usb: bool,
+    pub link: bool,
+    pub caps_lock: bool,
+    pub layer: bool,
+}
+
+impl LedConfig {
+    /// Shows every state
+    pub const ALL: Self = Self {
+        usb: true,
+        link: true,
+        caps_lock: true,
+        layer: true,
+    };
+
+    /// Pattern of the most important enabled state
+    pub fn pattern(&self, indicators: &Indicators) -> Pattern {
+        if self.usb && !indicators.usb_configured {
+            Pattern::Blink {
+                on_ms: 100,
+                period_ms: 1000,
+            }
+        } else if self.link && !indicators.link_up {
+            Pattern::Blink {
+                on_ms: 500,
+                period_ms: 1000,
+            }
+        } else if self.caps_lock && indicators.caps_lock {
+            Pattern::On
+        } else if self.layer && indicators.layer > 0 {
+            Pattern::Flashes {
+                count: indicators.layer,
+            }
+        } else {
+            Pattern::Off
+        }
+    }
+}
+
+/// Duration of a flash of [`Pattern::Flashes`], and of the gap between two flashes
+const FLASH_MS: u32 = 150;
+
+/// Pause after the flashes of [`Pattern::Flashes`]
+const PAUSE_MS: u32 = 1000;
+
+/// LED level as a function of the time
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Pattern {
+    Off,
+    On,
+    /// On for `on_ms` at the start of every period
+    Blink { on_ms: u32, period_ms: u32 },
+    /// `count` flashes, then a pause
+    Flashes { count: u8 },
+}
+
+impl Pattern {
+    /// Returns true if the LED is on at `now_ms`
+    pub fn is_on(&self, now_ms: u32) -> bool {
+        match *self {
+            Pattern::Off => false,
+            Pattern::On => true,
+            Pattern::Blink { on_ms, period_ms } => now_ms % period_ms < on_ms,
+            Pattern::Flashes { count } => {
+                let flashes = u32::from(count) * 2 * FLASH_MS;
+                let t = now_ms % (flashes + PAUSE_MS);
+                t < flashes && t % (2 * FLASH_MS) < FLASH_MS
+            }
+        }
+    }
+}
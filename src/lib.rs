@@ -1,20 +1,54 @@
-#![no_main]
+#![cfg_attr(target_os = "none", no_main)]
 #![no_std]
 
+// The logic modules also build for the host, to be tested there, see `host-tests`
+#[cfg(target_os = "none")]
 use defmt_rtt as _; // global logger
 
+#[cfg(target_os = "none")]
 use stm32f4xx_hal as _; // memory layout
 
+#[cfg(target_os = "none")]
 use panic_probe as _;
 
+pub mod arbiter;
+#[cfg(target_os = "none")]
+pub mod bootloader;
+pub mod bounce;
+pub mod clock;
+pub mod diagnostic;
+pub mod dma;
+pub mod encoder;
+pub mod gesture;
+pub mod health;
+pub mod heatmap;
+pub mod i2c;
+pub mod joystick;
+pub mod keymap;
+pub mod layout;
+pub mod led;
+pub mod link;
+pub mod matrix;
+pub mod merge;
+pub mod position;
+pub mod protocol;
+pub mod report;
+pub mod settings;
+pub mod side;
+pub mod storage;
+pub mod transport;
+pub mod via;
+
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(target_os = "none")]
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
 }
 
 /// Terminates the application and makes `probe-run` exit with exit-code = 0
+#[cfg(target_os = "none")]
 pub fn exit() -> ! {
     loop {
         cortex_m::asm::bkpt();
@@ -24,7 +58,7 @@ pub fn exit() -> ! {
 // defmt-test 0.3.0 has the limitation that this `#[tests]` attribute can only be used
 // once within a crate. the module can be in any file but there can only be at most
 // one `#[tests]` module in this library crate
-#[cfg(test)]
+#[cfg(all(test, target_os = "none"))]
 #[defmt_test::tests]
 mod unit_tests {
     use defmt::assert;
@@ -0,0 +1,311 @@
+//! Messages exchanged by the halves over the serial link.
+//!
+//! Every message is sent in a frame made of [`SYNC`], the message kind, the payload length, the
+//! payload and a CRC-8 of kind, length and payload. The [`Decoder`] drops corrupted frames and
+//! looks for the next [`SYNC`] byte to resynchronize.
+
+use crate::{encoder::Direction, joystick::Motion, position::KeyPosition, side::Side};
+use heapless::Vec;
+
+/// First byte of every frame
+pub const SYNC: u8 = 0x7e;
+
+/// Largest payload of a message
+pub const MAX_PAYLOAD: usize = 8;
+
+/// Largest frame, SYNC, kind, length and CRC included
+pub const MAX_FRAME: usize = MAX_PAYLOAD + 4;
+
+const KIND_HELLO: u8 = 0x01;
+const KIND_EVENT: u8 = 0x02;
+const KIND_PING: u8 = 0x03;
+const KIND_PONG: u8 = 0x04;
+const KIND_TOKEN: u8 = 0x05;
+const KIND_HEARTBEAT: u8 = 0x06;
+const KIND_ENCODER: u8 = 0x07;
+const KIND_MOTION: u8 = 0x08;
+
+/// Message sent to the other half
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Message {
+    /// Sent at startup to check that the halves are on different sides
+    Hello { side: Side },
+    /// A key changed state at `time_us` on the clock of the sender
+    Event {
+        position: KeyPosition,
+        pressed: bool,
+        time_us: u32,
+    },
+    /// Asks for the time of the other half, `origin_us` being the time of the sender
+    Ping { origin_us: u32 },
+    /// Answers a [`Message::Ping`] with its origin and the time it was received at
+    Pong { origin_us: u32, remote_us: u32 },
+    /// Passes the bus to the other half, see [`Arbiter`](crate::arbiter::Arbiter)
+    Token,
+    /// Sent periodically to show that the link works, with the keys pressed on the sender half
+    /// as the bits of a [`KeySet`](crate::health::KeySet), see [`health`](crate::health)
+    Heartbeat { pressed: u64 },
+    /// The encoder at `index` on the keyboard turned by a detent
+    Encoder { index: u8, direction: Direction },
+    /// The joystick moved the pointer, for the half connected to the host to report
+    Motion(Motion),
+}
+
+impl Message {
+    /// Encodes the message in a frame
+    pub fn encode(&self) -> Vec<u8, MAX_FRAME> {
+        let mut payload: Vec<u8, MAX_PAYLOAD> = Vec::new();
+        let kind = match *self {
+            Message::Hello { side } => {
+                payload.push(side.to_byte()).ok();
+                KIND_HELLO
+            }
+            Message::Event {
+                position,
+                pressed,
+                time_us,
+            } => {
+                payload
+                    .extend_from_slice(&[position.row.0, position.col.0, pressed as u8])
+                    .ok();
+                payload.extend_from_slice(&time_us.to_be_bytes()).ok();
+                KIND_EVENT
+            }
+            Message::Ping { origin_us } => {
+                payload.extend_from_slice(&origin_us.to_be_bytes()).ok();
+                KIND_PING
+            }
+            Message::Pong {
+                origin_us,
+                remote_us,
+            } => {
+                payload.extend_from_slice(&origin_us.to_be_bytes()).ok();
+                payload.extend_from_slice(&remote_us.to_be_bytes()).ok();
+                KIND_PONG
+            }
+            Message::Token => KIND_TOKEN,
+            Message::Heartbeat { pressed } => {
+                payload.extend_from_slice(&pressed.to_be_bytes()).ok();
+                KIND_HEARTBEAT
+            }
+            Message::Encoder { index, direction } => {
+                payload
+                    .extend_from_slice(&[index, direction.to_byte()])
+                    .ok();
+                KIND_ENCODER
+            }
+            Message::Motion(motion) => {
+                payload
+                    .extend_from_slice(&[motion.dx as u8, motion.dy as u8])
+                    .ok();
+                KIND_MOTION
+            }
+        };
+        let mut frame = Vec::new();
+        frame.push(SYNC).ok();
+        frame.push(kind).ok();
+        frame.push(payload.len() as u8).ok();
+        frame.extend_from_slice(&payload).ok();
+        frame.push(crc8(&frame[1..])).ok();
+        frame
+    }
+
+    /// Decodes the kind and payload of a frame
+    fn decode(kind: u8, payload: &[u8]) -> Option<Self> {
+        match (kind, payload) {
+            (KIND_HELLO, &[side]) => Side::from_byte(side).map(|side| Message::Hello { side }),
+            (KIND_EVENT, &[row, col, pressed, t0, t1, t2, t3]) if pressed <= 1 => {
+                Some(Message::Event {
+                    position: KeyPosition::new(row, col),
+                    pressed: pressed == 1,
+                    time_us: u32::from_be_bytes([t0, t1, t2, t3]),
+                })
+            }
+            (KIND_PING, &[o0, o1, o2, o3]) => Some(Message::Ping {
+                origin_us: u32::from_be_bytes([o0, o1, o2, o3]),
+            }),
+            (KIND_PONG, &[o0, o1, o2, o3, r0, r1, r2, r3]) => Some(Message::Pong {
+                origin_us: u32::from_be_bytes([o0, o1, o2, o3]),
+                remote_us: u32::from_be_bytes([r0, r1, r2, r3]),
+            }),
+            (KIND_TOKEN, &[]) => Some(Message::Token),
+            (KIND_HEARTBEAT, &[p0, p1, p2, p3, p4, p5, p6, p7]) => Some(Message::Heartbeat {
+                pressed: u64::from_be_bytes([p0, p1, p2, p3, p4, p5, p6, p7]),
+            }),
+            (KIND_ENCODER, &[index, direction]) => Direction::from_byte(direction)
+                .map(|direction| Message::Encoder { index, direction }),
+            (KIND_MOTION, &[dx, dy]) => Some(Message::Motion(Motion {
+                dx: dx as i8,
+                dy: dy as i8,
+            })),
+            _ => None,
+        }
+    }
+}
+
+/// CRC-8 with polynomial 0x07
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Reason a frame is dropped
+enum FrameError {
+    Crc,
+    Invalid,
+}
+
+/// Reassembles messages from the received bytes
+#[derive(Clone, Debug, Default)]
+pub struct Decoder {
+    frame: Vec<u8, MAX_FRAME>,
+    crc_errors: u32,
+    invalid: u32,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            frame: Vec::new(),
+            crc_errors: 0,
+            invalid: 0,
+        }
+    }
+
+    /// Number of frames dropped so far because of a wrong CRC
+    pub fn crc_errors(&self) -> u32 {
+        self.crc_errors
+    }
+
+    /// Number of frames dropped so far because of an invalid length or content
+    pub fn invalid(&self) -> u32 {
+        self.invalid
+    }
+
+    /// Adds a received byte, returns the message it completes
+    pub fn push(&mut self, byte: u8) -> Option<Message> {
+        if self.frame.is_empty() && byte != SYNC {
+            return None;
+        }
+        self.frame.push(byte).ok();
+
+        loop {
+            match self.check() {
+                Ok(Some(message)) => {
+                    self.frame.clear();
+                    return Some(message);
+                }
+                Ok(None) => return None,
+                Err(error) => {
+                    match error {
+                        FrameError::Crc => self.crc_errors = self.crc_errors.wrapping_add(1),
+                        FrameError::Invalid => self.invalid = self.invalid.wrapping_add(1),
+                    }
+                    self.resync();
+                }
+            }
+        }
+    }
+
+    /// Decodes the buffered frame, `Ok(None)` if it is incomplete
+    fn check(&self) -> Result<Option<Message>, FrameError> {
+        let len = match self.frame.get(2) {
+            Some(&len) if usize::from(len) <= MAX_PAYLOAD => usize::from(len),
+            Some(_) => return Err(FrameError::Invalid),
+            None => return Ok(None),
+        };
+        if self.frame.len() < len + 4 {
+            return Ok(None);
+        }
+        let (body, crc) = self.frame[1..].split_at(len + 2);
+        if crc8(body) != crc[0] {
+            defmt::debug!("Dropping corrupted frame");
+            return Err(FrameError::Crc);
+        }
+        Message::decode(body[0], &body[2..])
+            .map(Some)
+            .ok_or(FrameError::Invalid)
+    }
+
+    /// Drops the buffered frame up to the next SYNC byte
+    fn resync(&mut self) {
+        let next = self.frame[1..]
+            .iter()
+            .position(|&b| b == SYNC)
+            .map_or(self.frame.len(), |i| i + 1);
+        let rest: Vec<u8, MAX_FRAME> = Vec::from_slice(&self.frame[next..]).unwrap_or_default();
+        self.frame = rest;
+    }
+}
+
+/// Error reported by the UART
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum UartError {
+    /// No stop bit where expected
+    Framing,
+    /// Noise detected on the line
+    Noise,
+    /// A byte was received before the previous one was read
+    Overrun,
+}
+
+/// Traffic and error counters of the link, wrapping around
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, defmt::Format)]
+pub struct LinkStats {
+    pub rx_bytes: u32,
+    pub tx_bytes: u32,
+    pub rx_frames: u32,
+    pub tx_frames: u32,
+    /// Frames not sent because the transmit queue was full
+    pub tx_dropped: u32,
+    /// Received frames dropped as invalid or for lack of room
+    pub rx_dropped: u32,
+    /// Received frames dropped because of a wrong CRC
+    pub crc_errors: u32,
+    pub framing_errors: u32,
+    pub noise_errors: u32,
+    pub overruns: u32,
+}
+
+impl LinkStats {
+    pub const fn new() -> Self {
+        Self {
+            rx_bytes: 0,
+            tx_bytes: 0,
+            rx_frames: 0,
+            tx_frames: 0,
+            tx_dropped: 0,
+            rx_dropped: 0,
+            crc_errors: 0,
+            framing_errors: 0,
+            noise_errors: 0,
+            overruns: 0,
+        }
+    }
+
+    /// Counts accumulated since `earlier`
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            rx_bytes: self.rx_bytes.wrapping_sub(earlier.rx_bytes),
+            tx_bytes: self.tx_bytes.wrapping_sub(earlier.tx_bytes),
+            rx_frames: self.rx_frames.wrapping_sub(earlier.rx_frames),
+            tx_frames: self.tx_frames.wrapping_sub(earlier.tx_frames),
+            tx_dropped: self.tx_dropped.wrapping_sub(earlier.tx_dropped),
+            rx_dropped: self.rx_dropped.wrapping_sub(earlier.rx_dropped),
+            crc_errors: self.crc_errors.wrapping_sub(earlier.crc_errors),
+            framing_errors: self.framing_errors.wrapping_sub(earlier.framing_errors),
+            noise_errors: self.noise_errors.wrapping_sub(earlier.noise_errors),
+            overruns: self.overruns.wrapping_sub(earlier.overruns),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Scanning of the key matrix, with a wake mode between key presses.
+//!
+//! The outputs are driven to the active level one at a time and the inputs read for each of
+//! them, a pressed key bringing its input to the active level through its diode. Which lines are
+//! outputs depends on the [`MatrixConfig`]: the diodes must conduct from the outputs to the
+//! inputs when the outputs are high, or from the inputs to the outputs when they are low.
+//!
+//! Like QMK, the scan waits for the lines to settle after selecting an output, before reading
+//! the inputs, and after unselecting an output that had a key pressed, before selecting the next
+//! one: the capacitance of the lines and of a pressed key keeps the previous level for a while.
+//!
+//! While no key is pressed the matrix can sleep: [`Matrix::sleep`] drives every output active,
+//! so that pressing any key changes the level of its input and wakes the firmware through an
+//! external interrupt on the inputs. The next [`Matrix::scan`] resumes the normal scanning. The
+//! [`IdleTimer`] decides when to sleep.
+//!
+//! Without diodes, or with a missing or reversed one, three pressed keys at the corners of a
+//! rectangle also bring the input of the fourth corner to the active level. With
+//! [`MatrixConfig::ghost_filter`], a key newly seen pressed while the three other corners were
+//! already held is a possible ghost: it is not reported, and logged once, until one of the other
+//! corners is released. Keys seen pressed in the same scan are all reported.
+
+use crate::position::{MatrixEvent, MatrixPosition};
+use embedded_hal::{
+    blocking::delay::DelayUs,
+    digital::v2::{InputPin, OutputPin, PinState},
+};
+use heapless::Vec;
+
+/// Largest number of keys of a matrix
+pub const MAX_KEYS: usize = 64;
+
+/// Largest number of rows or columns of a matrix
+pub const MAX_LINES: usize = 16;
+
+/// Direction in which the diodes of the keys conduct
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum DiodeDirection {
+    /// From the column to the row, cathodes on the rows
+    Col2Row,
+    /// From the row to the column, cathodes on the columns
+    Row2Col,
+}
+
+/// Resistor pulling the inputs to their inactive level
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Pull {
+    Up,
+    Down,
+}
+
+/// Wiring of the key matrix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MatrixConfig {
+    pub diodes: DiodeDirection,
+    pub pull: Pull,
+    /// Wait after selecting an output, before reading the inputs
+    pub select_delay_us: u32,
+    /// Wait after unselecting an output with a pressed key, before selecting the next one
+    pub unselect_delay_us: u32,
+    /// Holds back the keys completing a rectangle of held keys, for matrices without diodes
+    pub ghost_filter: bool,
+}
+
+impl MatrixConfig {
+    /// Level of the driven output and of the inputs of its pressed keys, opposite to the pull
+    pub fn active_level(&self) -> PinState {
+        match self.pull {
+            Pull::Up => PinState::Low,
+            Pull::Down => PinState::High,
+        }
+    }
+
+    /// Returns true if the rows are the outputs, false if the columns are.
+    ///
+    /// Outputs driven low sink the current of the diodes, outputs driven high source it.
+    pub fn rows_driven(&self) -> bool {
+        (self.diodes == DiodeDirection::Col2Row) == (self.pull == Pull::Up)
+    }
+}
+
+impl Default for MatrixConfig {
+    /// Columns pulled up, rows driven low through the diodes, with the settle times of QMK
+    fn default() -> Self {
+        Self {
+            diodes: DiodeDirection::Col2Row,
+            pull: Pull::Up,
+            select_delay_us: 1,
+            unselect_delay_us: 30,
+            ghost_filter: false,
+        }
+    }
+}
+
+/// Key matrix with `ROWS` rows and `COLS` columns, of which either are the inputs
+pub struct Matrix<I, O, const ROWS: usize, const COLS: usize> {
+    config: MatrixConfig,
+    inputs: Vec<I, MAX_LINES>,
+    outputs: Vec<O, MAX_LINES>,
+    pressed: [[bool; COLS]; ROWS],
+    /// Keys read as pressed but not reported, as possible ghosts
+    ghosts: [[bool; COLS]; ROWS],
+    sleeping: bool,
+}
+
+impl<I, O, const ROWS: usize, const COLS: usize> Matrix<I, O, ROWS, COLS>
+where
+    I: InputPin,
+    O: OutputPin,
+{
+    /// Matrix scanning the given lines, the columns as inputs and the rows as outputs or the
+    /// other way around, as told by [`MatrixConfig::rows_driven`]
+    pub fn new(
+        config: MatrixConfig,
+        inputs: impl IntoIterator<Item = I>,
+        outputs: impl IntoIterator<Item = O>,
+    ) -> Self {
+        assert!(ROWS <= MAX_LINES && COLS <= MAX_LINES && ROWS * COLS <= MAX_KEYS);
+        let inputs: Vec<I, MAX_LINES> = inputs.into_iter().collect();
+        let mut outputs: Vec<O, MAX_LINES> = outputs.into_iter().collect();
+        let (input_lines, output_lines) = if config.rows_driven() {
+            (COLS, ROWS)
+        } else {
+            (ROWS, COLS)
+        };
+        assert!(inputs.len() == input_lines && outputs.len() == output_lines);
+
+        for output in &mut outputs {
+            output.set_state(!config.active_level()).ok();
+        }
+        Self {
+            config,
+            inputs,
+            outputs,
+            pressed: [[false; COLS]; ROWS],
+            ghosts: [[false; COLS]; ROWS],
+            sleeping: false,
+        }
+    }
+
+    /// Input pins, to configure their interrupts
+    pub fn inputs_mut(&mut self) -> &mut [I] {
+        &mut self.inputs
+    }
+
+    /// Longest settle time of a [`Matrix::scan`], right after sleeping with a key pressed on
+    /// every output, to check against the scan period
+    pub fn max_scan_us(&self) -> u32 {
+        let outputs = self.outputs.len() as u32;
+        self.config.unselect_delay_us
+            + outputs * (self.config.select_delay_us + self.config.unselect_delay_us)
+    }
+
+    /// Reads every key, returns the ones that changed state since the previous scan.
+    ///
+    /// With the ghost filter, possible ghosts are left out until the rectangle they complete is
+    /// broken. The settle times of the [`MatrixConfig`] are waited with `delay`.
+    pub fn scan(&mut self, delay: &mut impl DelayUs<u32>) -> Vec<MatrixEvent, MAX_KEYS> {
+        let active = self.config.active_level();
+        if self.sleeping {
+            self.sleeping = false;
+            for output in &mut self.outputs {
+                output.set_state(!active).ok();
+            }
+            delay.delay_us(self.config.unselect_delay_us);
+        }
+
+        let rows_driven = self.config.rows_driven();
+        let mut keys = [[false; COLS]; ROWS];
+        for (o, output) in self.outputs.iter_mut().enumerate() {
+            output.set_state(active).ok();
+            delay.delay_us(self.config.select_delay_us);
+            let mut any_pressed = false;
+            for (i, input) in self.inputs.iter().enumerate() {
+                let (row, col) = if rows_driven { (o, i) } else { (i, o) };
+                keys[row][col] = input
+                    .is_high()
+                    .map(|high| high == (active == PinState::High))
+                    .unwrap_or(false);
+                any_pressed |= keys[row][col];
+            }
+            output.set_state(!active).ok();
+            if any_pressed {
+                delay.delay_us(self.config.unselect_delay_us);
+            }
+        }
+
+        // Keys pressed before this scan and still pressed, the corners of a ghost
+        let mut held = [[false; COLS]; ROWS];
+        for (held, (keys, pressed)) in held.iter_mut().zip(keys.iter().zip(&self.pressed)) {
+            for (held, (&key, &pressed)) in held.iter_mut().zip(keys.iter().zip(pressed)) {
+                *held = key && pressed;
+            }
+        }
+
+        let mut events = Vec::new();
+        for o in 0..self.outputs.len() {
+            for i in 0..self.inputs.len() {
+                let (row, col) = if rows_driven { (o, i) } else { (i, o) };
+                let pressed = keys[row][col];
+                if !pressed {
+                    self.ghosts[row][col] = false;
+                }
+                if pressed == self.pressed[row][col] {
+                    continue;
+                }
+                let position = MatrixPosition::new(row as u8, col as u8);
+                if pressed && self.config.ghost_filter {
+                    if let Some(corner) = rectangle(&held, row, col) {
+                        if !self.ghosts[row][col] {
+                            self.ghosts[row][col] = true;
+                            defmt::warn!(
+                                "Possible ghost key at {}, pressed with {}, {} and {}",
+                                position,
+                                MatrixPosition::new(corner.row.0, col as u8),
+                                MatrixPosition::new(row as u8, corner.col.0),
+                                corner
+                            );
+                        }
+                        continue;
+                    }
+                }
+                self.ghosts[row][col] = false;
+                self.pressed[row][col] = pressed;
+                events.push(MatrixEvent { position, pressed }).ok();
+            }
+        }
+        events
+    }
+
+    /// Returns true if a key was pressed at the last scan
+    pub fn any_pressed(&self) -> bool {
+        self.pressed.iter().flatten().any(|&pressed| pressed)
+    }
+
+    /// Drives every output active until the next scan, pressing a key then changes the level of
+    /// its input
+    pub fn sleep(&mut self) {
+        self.sleeping = true;
+        let active = self.config.active_level();
+        for output in &mut self.outputs {
+            output.set_state(active).ok();
+        }
+    }
+}
+
+/// Finds the corner opposite to `(row, col)` of a rectangle of held keys
+fn rectangle<const ROWS: usize, const COLS: usize>(
+    keys: &[[bool; COLS]; ROWS],
+    row: usize,
+    col: usize,
+) -> Option<MatrixPosition> {
+    (0..ROWS)
+        .filter(|&other_row| other_row != row && keys[other_row][col])
+        .find_map(|other_row| {
+            (0..COLS)
+                .find(|&other_col| {
+                    other_col != col && keys[row][other_col] && keys[other_row][other_col]
+                })
+                .map(|other_col| MatrixPosition::new(other_row as u8, other_col as u8))
+        })
+}
+
+/// Decides when the matrix sleeps, once nothing happened for `idle_ms`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdleTimer {
+    idle_ms: u32,
+    /// Start of the current idle period
+    since: Option<u32>,
+}
+
+impl IdleTimer {
+    pub const fn new(idle_ms: u32) -> Self {
+        Self {
+            idle_ms,
+            since: None,
+        }
+    }
+
+    /// Updates the timer at `now_ms`, `busy` if a key is pressed or an event pending. Returns
+    /// true when the matrix should sleep, the timer then starts over.
+    pub fn update(&mut self, now_ms: u32, busy: bool) -> bool {
+        if busy {
+            self.since = None;
+            return false;
+        }
+        let since = *self.since.get_or_insert(now_ms);
+        if now_ms.wrapping_sub(since) < self.idle_ms {
+            return false;
+        }
+        self.since = None;
+        true
+    }
+}
+
+/// Mask of the EXTI lines of the pins with the given numbers, `None` if two pins share a line.
+///
+/// Each EXTI line is connected to the pin with its number on a single port, so pins like PA4 and
+/// PB4 cannot both wake the matrix.
+pub fn exti_lines(pins: impl IntoIterator<Item = u8>) -> Option<u32> {
+    pins.into_iter().try_fold(0, |mask: u32, pin| {
+        let line = 1 << pin;
+        if mask & line == 0 {
+            Some(mask | line)
+        } else {
+            None
+        }
+    })
+}
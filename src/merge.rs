@@ -0,0 +1,57 @@
+//! Merging of the key events of the two halves in press order.
+//!
+//! Remote events reach the master some time after they happened, so events are held for a
+//! fixed delay, longer than the link latency, and released by increasing timestamp. Events with
+//! the same timestamp are released in the order they were pushed.
+
+use heapless::Vec;
+
+/// Returns true if `a` is before `b`, for wrapping times
+fn is_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Holds up to `N` timestamped events, times in microseconds
+#[derive(Clone, Debug)]
+pub struct EventMerger<E, const N: usize> {
+    delay_us: u32,
+    events: Vec<(u32, E), N>,
+}
+
+impl<E, const N: usize> EventMerger<E, N> {
+    /// Merger releasing the events `delay_us` after their timestamp
+    pub const fn new(delay_us: u32) -> Self {
+        Self {
+            delay_us,
+            events: Vec::new(),
+        }
+    }
+
+    /// Adds an event that happened at `time_us`, returns it back if the merger is full
+    pub fn push(&mut self, time_us: u32, event: E) -> Result<(), E> {
+        self.events
+            .push((time_us, event))
+            .map_err(|(_, event)| event)
+    }
+
+    /// Returns true if no event is held
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Removes the earliest event if it is older than the delay at `now_us`
+    pub fn pop_ready(&mut self, now_us: u32) -> Option<(u32, E)> {
+        let index = (0..self.events.len()).reduce(|earliest, i| {
+            if is_before(self.events[i].0, self.events[earliest].0) {
+                i
+            } else {
+                earliest
+            }
+        })?;
+        let time_us = self.events[index].0;
+        if is_before(now_us, time_us.wrapping_add(self.delay_us)) {
+            return None;
+        }
+        Some(self.events.remove(index))
+    }
+}
@@ -0,0 +1,148 @@
+//! Typed coordinates of the keys.
+//!
+//! The [`Matrix`](crate::matrix::Matrix) of a half reports [`MatrixPosition`]s, on the rows and
+//! columns of that half. The [`HalfMap`] of the half is the only place turning them into the
+//! [`KeyPosition`]s of the whole keyboard, used by the link, the keymap and the HID state. Rows
+//! and columns have their own types, so swapped coordinates do not compile.
+
+/// Row of a key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, defmt::Format)]
+pub struct Row(pub u8);
+
+impl Row {
+    pub fn index(self) -> usize {
+        usize::from(self.0)
+    }
+}
+
+/// Column of a key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, defmt::Format)]
+pub struct Col(pub u8);
+
+impl Col {
+    pub fn index(self) -> usize {
+        usize::from(self.0)
+    }
+}
+
+/// Position of a key on the matrix of a half
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, defmt::Format)]
+pub struct MatrixPosition {
+    pub row: Row,
+    pub col: Col,
+}
+
+impl MatrixPosition {
+    pub const fn new(row: u8, col: u8) -> Self {
+        Self {
+            row: Row(row),
+            col: Col(col),
+        }
+    }
+}
+
+/// Position of a key on the whole keyboard
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, defmt::Format)]
+pub struct KeyPosition {
+    pub row: Row,
+    pub col: Col,
+}
+
+impl KeyPosition {
+    pub const fn new(row: u8, col: u8) -> Self {
+        Self {
+            row: Row(row),
+            col: Col(col),
+        }
+    }
+}
+
+/// Key pressed or released on the matrix of a half
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct MatrixEvent {
+    pub position: MatrixPosition,
+    pub pressed: bool,
+}
+
+/// Key pressed or released on the keyboard
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct KeyEvent {
+    pub position: KeyPosition,
+    pub pressed: bool,
+}
+
+/// Maps the positions on the matrix of a half to the positions on the keyboard
+///
+/// The table is indexed by the row and column lines of the matrix, whichever of them are driven.
+/// Each entry is the key of the keyboard wired there, or `None` where no switch is fitted, so
+/// mirrored halves and keys outside the grid of the matrix, like thumb clusters, are listed
+/// explicitly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HalfMap<const ROWS: usize, const COLS: usize> {
+    keys: [[Option<KeyPosition>; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> HalfMap<ROWS, COLS> {
+    pub const fn new(keys: [[Option<KeyPosition>; COLS]; ROWS]) -> Self {
+        Self { keys }
+    }
+
+    /// Half whose columns start at `col_offset` on the keyboard, in the order of the matrix
+    pub const fn offset(col_offset: u8) -> Self {
+        Self::grid(col_offset, false)
+    }
+
+    /// Half whose columns start at `col_offset` on the keyboard, the last column of the matrix
+    /// first
+    pub const fn mirrored(col_offset: u8) -> Self {
+        Self::grid(col_offset, true)
+    }
+
+    const fn grid(col_offset: u8, mirrored: bool) -> Self {
+        let mut keys = [[None; COLS]; ROWS];
+        let mut row = 0;
+        while row < ROWS {
+            let mut col = 0;
+            while col < COLS {
+                let shift = if mirrored { COLS - 1 - col } else { col };
+                keys[row][col] = Some(KeyPosition::new(row as u8, col_offset + shift as u8));
+                col += 1;
+            }
+            row += 1;
+        }
+        Self { keys }
+    }
+
+    /// Removes the key at `position` of the matrix, where no switch is fitted
+    pub const fn without(mut self, position: MatrixPosition) -> Self {
+        self.keys[position.row.0 as usize][position.col.0 as usize] = None;
+        self
+    }
+
+    /// Places the key at `position` of the matrix on the keyboard
+    pub const fn with(mut self, position: MatrixPosition, key: KeyPosition) -> Self {
+        self.keys[position.row.0 as usize][position.col.0 as usize] = Some(key);
+        self
+    }
+
+    /// Key of the keyboard at `position` of the matrix, `None` without switch
+    pub fn key(&self, position: MatrixPosition) -> Option<KeyPosition> {
+        self.keys
+            .get(position.row.index())
+            .and_then(|row| row.get(position.col.index()))
+            .copied()
+            .flatten()
+    }
+
+    /// Keys of the keyboard on the half, in the order of the matrix
+    pub fn keys(&self) -> impl Iterator<Item = KeyPosition> + '_ {
+        self.keys.iter().flatten().flatten().copied()
+    }
+
+    pub fn event(&self, event: MatrixEvent) -> Option<KeyEvent> {
+        self.key(event.position).map(|position| KeyEvent {
+            position,
+            pressed: event.pressed,
+        })
+    }
+}
@@ -0,0 +1,411 @@
+//! Host configuration protocol spoken over the vendor-defined raw HID interface.
+//!
+//! Every request and response is a [`REPORT_SIZE`] bytes report. The first byte of a request
+//! is its [`CommandId`], followed by the arguments. The response echoes the command id, then
+//! carries a [`Status`] byte and the payload. Multi-byte values are big endian.
+//!
+//! | Command           | Arguments                          | Payload                   |
+//! |-------------------|------------------------------------|---------------------------|
+//! | `GetVersion`      |                                    | major, minor, patch       |
+//! | `GetLayerCount`   |                                    | layer count               |
+//! | `GetKeycode`      | layer, row, col                    | keycode (u16)             |
+//! | `SetKeycode`      | layer, row, col, keycode (u16)     |                           |
+//! | `ResetKeymap`     |                                    |                           |
+//! | `Bootloader`      |                                    |                           |
+//! | `GetSide`         |                                    | side                      |
+//! | `SetSide`         | side                               |                           |
+//! | `SetBounceStats`  | enabled (0 or 1)                   |                           |
+//! | `DumpBounceStats` |                                    |                           |
+//! | `DumpDiagnostics` |                                    |                           |
+//!
+//! Sides are encoded as in [`Side::to_byte`], `0xff` meaning that no side is stored. A new
+//! side is used from the next boot.
+//!
+//! Enabling the bounce statistics clears them. After answering `DumpBounceStats` the firmware
+//! sends the statistics in reports of their own, described in the [`bounce`](crate::bounce)
+//! module. After answering `DumpDiagnostics` it sends the findings of the matrix test kept since
+//! the previous dump the same way, described in the [`diagnostic`](crate::diagnostic) module.
+//!
+//! Keycodes are the ones of [`Action`](crate::keymap::Action). Requests starting with a VIA
+//! command id are handled by the [`via`](crate::via) module instead, the ones starting with a
+//! heatmap command id by the [`heatmap`](crate::heatmap) module.
+
+use crate::{
+    keymap::{Action, Keymap, KeymapError},
+    settings::Settings,
+    side::Side,
+    via,
+};
+use core::convert::TryFrom;
+
+/// Size of the raw HID input and output reports
+pub const REPORT_SIZE: usize = 32;
+
+/// Report descriptor of the raw HID interface: usage page 0xFF60, usage 0x61, one 32 bytes
+/// input report and one 32 bytes output report.
+#[rustfmt::skip]
+pub const RAW_HID_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xff,        // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,              // Usage (0x61)
+    0xa1, 0x01,              // Collection (Application)
+    0x09, 0x62,              //   Usage (0x62)
+    0x15, 0x00,              //   Logical Minimum (0)
+    0x26, 0xff, 0x00,        //   Logical Maximum (255)
+    0x95, REPORT_SIZE as u8, //   Report Count (32)
+    0x75, 0x08,              //   Report Size (8)
+    0x81, 0x02,              //   Input (Data, Variable, Absolute)
+    0x09, 0x63,              //   Usage (0x63)
+    0x15, 0x00,              //   Logical Minimum (0)
+    0x26, 0xff, 0x00,        //   Logical Maximum (255)
+    0x95, REPORT_SIZE as u8, //   Report Count (32)
+    0x75, 0x08,              //   Report Size (8)
+    0x91, 0x02,              //   Output (Data, Variable, Absolute)
+    0xc0,                    // End Collection
+];
+
+/// Firmware version as reported by [`Command::GetVersion`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+/// Version of the running firmware
+pub const FIRMWARE_VERSION: Version = Version {
+    major: parse_u8(env!("CARGO_PKG_VERSION_MAJOR")),
+    minor: parse_u8(env!("CARGO_PKG_VERSION_MINOR")),
+    patch: parse_u8(env!("CARGO_PKG_VERSION_PATCH")),
+};
+
+const fn parse_u8(s: &str) -> u8 {
+    let bytes = s.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0');
+        i += 1;
+    }
+    value
+}
+
+/// First byte of a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum CommandId {
+    GetVersion = 0xe0,
+    GetLayerCount = 0xe1,
+    GetKeycode = 0xe2,
+    SetKeycode = 0xe3,
+    ResetKeymap = 0xe4,
+    Bootloader = 0xe5,
+    GetSide = 0xe6,
+    SetSide = 0xe7,
+    SetBounceStats = 0xe8,
+    DumpBounceStats = 0xe9,
+    DumpDiagnostics = 0xee,
+}
+
+impl TryFrom<u8> for CommandId {
+    type Error = Status;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0xe0 => Ok(CommandId::GetVersion),
+            0xe1 => Ok(CommandId::GetLayerCount),
+            0xe2 => Ok(CommandId::GetKeycode),
+            0xe3 => Ok(CommandId::SetKeycode),
+            0xe4 => Ok(CommandId::ResetKeymap),
+            0xe5 => Ok(CommandId::Bootloader),
+            0xe6 => Ok(CommandId::GetSide),
+            0xe7 => Ok(CommandId::SetSide),
+            0xe8 => Ok(CommandId::SetBounceStats),
+            0xe9 => Ok(CommandId::DumpBounceStats),
+            0xee => Ok(CommandId::DumpDiagnostics),
+            _ => Err(Status::UnknownCommand),
+        }
+    }
+}
+
+/// Outcome of a request, second byte of a response
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0x00,
+    UnknownCommand = 0x01,
+    InvalidArgument = 0x02,
+    Unsupported = 0x03,
+}
+
+impl From<KeymapError> for Status {
+    fn from(error: KeymapError) -> Self {
+        match error {
+            KeymapError::OutOfRange => Status::InvalidArgument,
+            KeymapError::Unsupported => Status::Unsupported,
+        }
+    }
+}
+
+/// Request sent by the host
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Command {
+    /// Reads the firmware version
+    GetVersion,
+    /// Reads the number of layers, base layer included
+    GetLayerCount,
+    /// Reads the keycode at a position
+    GetKeycode { layer: u8, row: u8, col: u8 },
+    /// Writes the keycode at a position
+    SetKeycode {
+        layer: u8,
+        row: u8,
+        col: u8,
+        keycode: u16,
+    },
+    /// Restores the compiled-in keymap
+    ResetKeymap,
+    /// Resets into the DFU bootloader after answering
+    Bootloader,
+    /// Reads the stored side of the half
+    GetSide,
+    /// Stores the side of the half
+    SetSide { side: Side },
+    /// Starts or stops collecting the bounce statistics
+    SetBounceStats { enabled: bool },
+    /// Sends the bounce statistics after answering
+    DumpBounceStats,
+    /// Sends the pending findings of the matrix test after answering
+    DumpDiagnostics,
+}
+
+impl Command {
+    /// Identifier of the command
+    pub fn id(&self) -> CommandId {
+        match self {
+            Command::GetVersion => CommandId::GetVersion,
+            Command::GetLayerCount => CommandId::GetLayerCount,
+            Command::GetKeycode { .. } => CommandId::GetKeycode,
+            Command::SetKeycode { .. } => CommandId::SetKeycode,
+            Command::ResetKeymap => CommandId::ResetKeymap,
+            Command::Bootloader => CommandId::Bootloader,
+            Command::GetSide => CommandId::GetSide,
+            Command::SetSide { .. } => CommandId::SetSide,
+            Command::SetBounceStats { .. } => CommandId::SetBounceStats,
+            Command::DumpBounceStats => CommandId::DumpBounceStats,
+            Command::DumpDiagnostics => CommandId::DumpDiagnostics,
+        }
+    }
+
+    /// Decodes a request report
+    pub fn decode(report: &[u8; REPORT_SIZE]) -> Result<Self, Status> {
+        Ok(match CommandId::try_from(report[0])? {
+            CommandId::GetVersion => Command::GetVersion,
+            CommandId::GetLayerCount => Command::GetLayerCount,
+            CommandId::GetKeycode => Command::GetKeycode {
+                layer: report[1],
+                row: report[2],
+                col: report[3],
+            },
+            CommandId::SetKeycode => Command::SetKeycode {
+                layer: report[1],
+                row: report[2],
+                col: report[3],
+                keycode: u16::from_be_bytes([report[4], report[5]]),
+            },
+            CommandId::ResetKeymap => Command::ResetKeymap,
+            CommandId::Bootloader => Command::Bootloader,
+            CommandId::GetSide => Command::GetSide,
+            CommandId::SetSide => Command::SetSide {
+                side: Side::from_byte(report[1]).ok_or(Status::InvalidArgument)?,
+            },
+            CommandId::SetBounceStats => Command::SetBounceStats {
+                enabled: match report[1] {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(Status::InvalidArgument),
+                },
+            },
+            CommandId::DumpBounceStats => Command::DumpBounceStats,
+            CommandId::DumpDiagnostics => Command::DumpDiagnostics,
+        })
+    }
+
+    /// Encodes the request report
+    pub fn encode(&self) -> [u8; REPORT_SIZE] {
+        let mut report = [0; REPORT_SIZE];
+        report[0] = self.id() as u8;
+        match *self {
+            Command::GetKeycode { layer, row, col } => {
+                report[1..4].copy_from_slice(&[layer, row, col]);
+            }
+            Command::SetKeycode {
+                layer,
+                row,
+                col,
+                keycode,
+            } => {
+                report[1..4].copy_from_slice(&[layer, row, col]);
+                report[4..6].copy_from_slice(&keycode.to_be_bytes());
+            }
+            Command::SetSide { side } => report[1] = side.to_byte(),
+            Command::SetBounceStats { enabled } => report[1] = enabled as u8,
+            _ => {}
+        }
+        report
+    }
+
+    /// Executes the command on `settings`, `defaults` is used by [`Command::ResetKeymap`]
+    pub fn apply<const R: usize, const C: usize, const L: usize>(
+        &self,
+        settings: &mut Settings<R, C, L>,
+        defaults: &Keymap<R, C, L>,
+    ) -> Result<Response, Status> {
+        let keymap = &mut settings.keymap;
+        match *self {
+            Command::GetVersion => Ok(Response::Version(FIRMWARE_VERSION)),
+            Command::GetLayerCount => Ok(Response::LayerCount(keymap.layer_count() as u8)),
+            Command::GetKeycode { layer, row, col } => keymap
+                .get(layer.into(), row.into(), col.into())
+                .map(|action| Response::Keycode(action.keycode()))
+                .map_err(Status::from),
+            Command::SetKeycode {
+                layer,
+                row,
+                col,
+                keycode,
+            } => {
+                let action = Action::from_keycode(keycode).ok_or(Status::Unsupported)?;
+                keymap.set(layer.into(), row.into(), col.into(), action)?;
+                Ok(Response::Done)
+            }
+            Command::ResetKeymap => {
+                *keymap = *defaults;
+                Ok(Response::Done)
+            }
+            Command::Bootloader
+            | Command::SetBounceStats { .. }
+            | Command::DumpBounceStats
+            | Command::DumpDiagnostics => Ok(Response::Done),
+            Command::GetSide => Ok(Response::Side(settings.side)),
+            Command::SetSide { side } => {
+                settings.side = Some(side);
+                Ok(Response::Done)
+            }
+        }
+    }
+
+    /// Work left to the firmware after the command succeeded
+    pub fn effect(&self) -> Effect {
+        match self {
+            Command::SetKeycode { .. } | Command::ResetKeymap => Effect::KeymapChanged,
+            Command::Bootloader => Effect::Bootloader,
+            Command::SetSide { .. } => Effect::SettingsChanged,
+            Command::SetBounceStats { enabled } => Effect::BounceStats { enabled: *enabled },
+            Command::DumpBounceStats => Effect::DumpBounceStats,
+            Command::DumpDiagnostics => Effect::DumpDiagnostics,
+            _ => Effect::None,
+        }
+    }
+}
+
+/// Work the firmware has to perform after answering a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Effect {
+    None,
+    /// The `GridState` must be rebuilt from the keymap and the settings saved
+    KeymapChanged,
+    /// The settings must be saved
+    SettingsChanged,
+    /// The MCU must reset into the bootloader, once the host had time to read the response
+    Bootloader,
+    /// The bounce statistics must be started or stopped
+    BounceStats {
+        enabled: bool,
+    },
+    /// The bounce statistics must be sent to the host
+    DumpBounceStats,
+    /// The pending findings of the diagnostics must be sent to the host
+    DumpDiagnostics,
+    /// The [`Heatmap`](crate::heatmap::Heatmap) must be saved
+    HeatmapChanged,
+}
+
+/// Answers a raw HID request and returns the response with the work left to the firmware
+pub fn handle_request<const R: usize, const C: usize, const L: usize>(
+    request: &[u8; REPORT_SIZE],
+    settings: &mut Settings<R, C, L>,
+    defaults: &Keymap<R, C, L>,
+) -> ([u8; REPORT_SIZE], Effect) {
+    if via::is_via_command(request[0]) {
+        let mut response = *request;
+        let effect = settings
+            .via
+            .handle(&mut response, &mut settings.keymap, defaults);
+        return (response, effect);
+    }
+
+    let command = Command::decode(request);
+    defmt::debug!("Command: {:?}", command);
+    let result = command.and_then(|command| command.apply(settings, defaults));
+    let effect = match (command, result) {
+        (Ok(command), Ok(_)) => command.effect(),
+        _ => Effect::None,
+    };
+    (encode_response(request[0], result), effect)
+}
+
+/// Payload of a successful response
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Response {
+    Version(Version),
+    LayerCount(u8),
+    Keycode(u16),
+    /// Stored side, if any
+    Side(Option<Side>),
+    /// The command has no payload
+    Done,
+}
+
+/// Encodes the response report to a request with the given id
+pub fn encode_response(id: u8, result: Result<Response, Status>) -> [u8; REPORT_SIZE] {
+    let mut report = [0; REPORT_SIZE];
+    report[0] = id;
+    match result {
+        Ok(response) => {
+            report[1] = Status::Ok as u8;
+            match response {
+                Response::Version(v) => report[2..5].copy_from_slice(&[v.major, v.minor, v.patch]),
+                Response::LayerCount(count) => report[2] = count,
+                Response::Keycode(keycode) => report[2..4].copy_from_slice(&keycode.to_be_bytes()),
+                Response::Side(side) => report[2] = side.map_or(0xff, Side::to_byte),
+                Response::Done => {}
+            }
+        }
+        Err(status) => report[1] = status as u8,
+    }
+    report
+}
+
+/// Decodes the response report to `command`, as done by host tools
+pub fn decode_response(command: &Command, report: &[u8; REPORT_SIZE]) -> Result<Response, Status> {
+    if report[0] != command.id() as u8 {
+        return Err(Status::InvalidArgument);
+    }
+    match report[1] {
+        0x00 => {}
+        0x01 => return Err(Status::UnknownCommand),
+        0x02 => return Err(Status::InvalidArgument),
+        _ => return Err(Status::Unsupported),
+    }
+    Ok(match command {
+        Command::GetVersion => Response::Version(Version {
+            major: report[2],
+            minor: report[3],
+            patch: report[4],
+        }),
+        Command::GetLayerCount => Response::LayerCount(report[2]),
+        Command::GetKeycode { .. } => Response::Keycode(u16::from_be_bytes([report[2], report[3]])),
+        Command::GetSide => Response::Side(Side::from_byte(report[2])),
+        _ => Response::Done,
+    })
+}
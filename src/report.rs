@@ -0,0 +1,74 @@
+//! Change-driven HID report scheduling.
+
+use heapless::Deque;
+
+/// Queue of HID reports waiting for the USB endpoint.
+///
+/// Reports are queued only when the key state changes and are handed to the endpoint one at a
+/// time, without ever waiting for it. When the host stops polling, the newest queued report is
+/// replaced instead of growing the queue, so producers are never blocked. The last report that
+/// was accepted is repeated once the idle period expires.
+pub struct ReportQueue<R, const N: usize> {
+    queue: Deque<R, N>,
+    last: Option<R>,
+    idle_ms: Option<u32>,
+    last_sent_ms: u32,
+}
+
+impl<R, const N: usize> ReportQueue<R, N> {
+    /// Creates an empty queue, `idle_ms` is `None` for an infinite idle period.
+    pub const fn new(idle_ms: Option<u32>) -> Self {
+        Self {
+            queue: Deque::new(),
+            last: None,
+            idle_ms,
+            last_sent_ms: 0,
+        }
+    }
+
+    /// Queues a report, replacing the newest queued one when the queue is full.
+    pub fn push(&mut self, report: R) {
+        if self.queue.is_full() {
+            self.queue.pop_back();
+        }
+        self.queue.push_back(report).ok();
+    }
+
+    /// Number of reports waiting to be sent.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Returns true when no report is waiting to be sent.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Offers at most one report to `push` and returns true if it was accepted.
+    ///
+    /// `push` is expected to return the number of bytes written, `Ok(0)` and errors are treated
+    /// as a busy endpoint and the report is retried on the next call.
+    pub fn send<E>(&mut self, now_ms: u32, push: impl FnOnce(&R) -> Result<usize, E>) -> bool {
+        let idle_expired = match self.idle_ms {
+            Some(idle_ms) => now_ms.wrapping_sub(self.last_sent_ms) >= idle_ms,
+            None => false,
+        };
+
+        let report = match (self.queue.front(), &self.last) {
+            (Some(report), _) => report,
+            (None, Some(last)) if idle_expired => last,
+            _ => return false,
+        };
+
+        match push(report) {
+            Ok(n) if n > 0 => {
+                self.last_sent_ms = now_ms;
+                if let Some(report) = self.queue.pop_front() {
+                    self.last = Some(report);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
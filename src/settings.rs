@@ -0,0 +1,73 @@
+//! Settings persisted in the flash [`Store`](crate::storage::Store).
+//!
+//! The payload of a settings record is the keymap as big endian keycodes, layer by layer and
+//! row by row, followed by the VIA layout options (u32, big endian) and the [`Side`] of the half
+//! (`0xff` when not set).
+
+use crate::{
+    keymap::{Action, Keymap},
+    side::Side,
+    via::Via,
+};
+
+/// State restored at boot, the compiled-in values are used when it cannot be loaded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Settings<const R: usize, const C: usize, const L: usize> {
+    pub keymap: Keymap<R, C, L>,
+    pub via: Via,
+    /// Side of the half, [`Side::resolve`] picks the side when not set
+    pub side: Option<Side>,
+}
+
+impl<const R: usize, const C: usize, const L: usize> Settings<R, C, L> {
+    /// Size of the encoded settings
+    pub const SIZE: usize = L * R * C * 2 + 4 + 1;
+
+    /// Settings with the given keymap and the default VIA state
+    pub fn new(keymap: Keymap<R, C, L>) -> Self {
+        Self {
+            keymap,
+            via: Via::default(),
+            side: None,
+        }
+    }
+
+    /// Encodes the settings in the first [`Self::SIZE`] bytes of `buf` and returns them
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> &'b [u8] {
+        let (keymap, rest) = buf[..Self::SIZE].split_at_mut(L * R * C * 2);
+        for (i, bytes) in keymap.chunks_exact_mut(2).enumerate() {
+            let keycode = self
+                .keymap
+                .get(i / (R * C), i / C % R, i % C)
+                .map(Action::keycode)
+                .unwrap_or_default();
+            bytes.copy_from_slice(&keycode.to_be_bytes());
+        }
+        rest[..4].copy_from_slice(&self.via.layout_options.to_be_bytes());
+        rest[4] = self.side.map_or(0xff, Side::to_byte);
+        &buf[..Self::SIZE]
+    }
+
+    /// Decodes encoded settings, returns `None` if they do not match the keymap size or hold
+    /// invalid actions
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::SIZE {
+            return None;
+        }
+        let (keymap, rest) = bytes.split_at(L * R * C * 2);
+        let mut settings = Self::new(Keymap::new([[[Action::No; C]; R]; L]));
+        for (i, bytes) in keymap.chunks_exact(2).enumerate() {
+            let action = Action::from_keycode(u16::from_be_bytes([bytes[0], bytes[1]]))?;
+            settings
+                .keymap
+                .set(i / (R * C), i / C % R, i % C, action)
+                .ok()?;
+        }
+        settings.via.layout_options = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        settings.side = match rest[4] {
+            0xff => None,
+            byte => Some(Side::from_byte(byte)?),
+        };
+        Some(settings)
+    }
+}
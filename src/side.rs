@@ -0,0 +1,46 @@
+//! Handedness of a half of the keyboard.
+
+/// Half of the keyboard, the right half reports its columns after the ones of the left half
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    /// Side of a half, the side jumper forces the right side over the stored setting.
+    ///
+    /// Halves without jumper and without stored setting are left halves.
+    pub fn resolve(jumper: bool, stored: Option<Side>) -> Self {
+        match (jumper, stored) {
+            (true, _) => Side::Right,
+            (false, Some(side)) => side,
+            (false, None) => Side::Left,
+        }
+    }
+
+    /// The other half
+    pub fn opposite(self) -> Self {
+        match self {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        }
+    }
+
+    /// Encodes the side in a byte
+    pub const fn to_byte(self) -> u8 {
+        match self {
+            Side::Left => 0,
+            Side::Right => 1,
+        }
+    }
+
+    /// Decodes a byte encoded by [`Side::to_byte`]
+    pub const fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Side::Left),
+            1 => Some(Side::Right),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Wear-levelled record store in a reserved flash sector.
+//!
+//! Records are appended one after the other in the sector and the last valid one is the
+//! current value. The sector is erased only when a new record does not fit anymore, so each
+//! erase cycle is shared by as many saves as fit in the sector.
+//!
+//! Every record starts with a header holding a magic value, the format version, the payload
+//! length and a CRC-32 of the payload. Records with a different version or a wrong checksum
+//! are skipped.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+/// Version of the record format, records of other versions are ignored
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Marks the beginning of a record
+const MAGIC: u32 = 0x4c53_5354;
+
+/// Value of erased flash words
+const ERASED: u32 = 0xffff_ffff;
+
+const HEADER_SIZE: usize = 12;
+
+/// Granularity of the records in flash
+const ALIGN: usize = 4;
+
+/// Record header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Header {
+    magic: u32,
+    version: u16,
+    len: u16,
+    crc: u32,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        bytes[4..6].copy_from_slice(&self.version.to_le_bytes());
+        bytes[6..8].copy_from_slice(&self.len.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.crc.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_SIZE]) -> Self {
+        Self {
+            magic: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            version: u16::from_le_bytes([bytes[4], bytes[5]]),
+            len: u16::from_le_bytes([bytes[6], bytes[7]]),
+            crc: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+        }
+    }
+}
+
+/// Space taken in flash by a record with `len` bytes of payload
+fn record_size(len: usize) -> usize {
+    (HEADER_SIZE + len).div_ceil(ALIGN) * ALIGN
+}
+
+/// CRC-32 (IEEE 802.3) of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Append-only record store in the flash region `offset..offset + size`.
+///
+/// The region must be aligned to the erase size of the flash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Store {
+    offset: u32,
+    size: u32,
+    /// Offset of the free space from the start of the region, `None` if it must be erased
+    next: Option<u32>,
+}
+
+impl Store {
+    /// Creates a store, [`Store::load`] must be called before saving
+    pub const fn new(offset: u32, size: u32) -> Self {
+        Self {
+            offset,
+            size,
+            next: None,
+        }
+    }
+
+    /// Reads the last valid record into `buf` and returns its length.
+    ///
+    /// Returns `Ok(None)` when the region holds no valid record. Records longer than `buf` are
+    /// skipped.
+    pub fn load<F: ReadNorFlash>(
+        &mut self,
+        flash: &mut F,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, F::Error> {
+        let mut found = None;
+        let mut position = 0;
+        self.next = None;
+
+        while position as usize + HEADER_SIZE <= self.size as usize {
+            let mut bytes = [0; HEADER_SIZE];
+            flash.read(self.offset + position, &mut bytes)?;
+            let header = Header::from_bytes(&bytes);
+
+            if header.magic == ERASED {
+                self.next = Some(position);
+                break;
+            }
+            let len = usize::from(header.len);
+            let size = record_size(len) as u32;
+            if header.magic != MAGIC || position + size > self.size {
+                // Unknown content, the region must be erased before writing again
+                break;
+            }
+
+            if header.version == FORMAT_VERSION && len <= buf.len() {
+                flash.read(self.offset + position + HEADER_SIZE as u32, &mut buf[..len])?;
+                if crc32(&buf[..len]) == header.crc {
+                    found = Some((position, len));
+                }
+            }
+            position += size;
+        }
+
+        match found {
+            Some((position, len)) => {
+                // Later invalid records may have overwritten `buf`
+                flash.read(self.offset + position + HEADER_SIZE as u32, &mut buf[..len])?;
+                Ok(Some(len))
+            }
+            None => {
+                defmt::debug!("No valid record found in flash");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns true if a record with `len` bytes of payload can be appended without erasing the
+    /// region. Erasing a large sector stalls the CPU for a second or more.
+    pub fn fits<F: ReadNorFlash>(&self, flash: &mut F, len: usize) -> Result<bool, F::Error> {
+        let size = record_size(len) as u32;
+        match self.next {
+            // An interrupted save can leave a partially written payload behind
+            Some(next) if next + size <= self.size => self.is_erased(flash, next, size),
+            _ => Ok(false),
+        }
+    }
+
+    /// Appends a record, erasing the region first if it is full or holds unknown content
+    pub fn save<F: NorFlash>(&mut self, flash: &mut F, payload: &[u8]) -> Result<(), F::Error> {
+        let size = record_size(payload.len()) as u32;
+        debug_assert!(size <= self.size && F::WRITE_SIZE <= ALIGN);
+
+        let position = match self.next {
+            Some(next) if self.fits(flash, payload.len())? => next,
+            _ => {
+                defmt::info!("Erasing flash region at {=u32:#x}", self.offset);
+                flash.erase(self.offset, self.offset + self.size)?;
+                0
+            }
+        };
+
+        let header = Header {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            len: payload.len() as u16,
+            crc: crc32(payload),
+        };
+        let start = self.offset + position;
+        let aligned = payload.len() / ALIGN * ALIGN;
+        flash.write(start + HEADER_SIZE as u32, &payload[..aligned])?;
+        if aligned < payload.len() {
+            let mut tail = [0xff; ALIGN];
+            tail[..payload.len() - aligned].copy_from_slice(&payload[aligned..]);
+            flash.write(start + (HEADER_SIZE + aligned) as u32, &tail)?;
+        }
+        // The header goes last, an interrupted write leaves an invalid record behind
+        flash.write(start, &header.to_bytes())?;
+
+        self.next = Some(position + size);
+        Ok(())
+    }
+
+    /// Returns true if the `size` bytes at `position` in the region are erased
+    fn is_erased<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        position: u32,
+        size: u32,
+    ) -> Result<bool, F::Error> {
+        let mut chunk = [0; 32];
+        let mut checked = 0;
+        while checked < size {
+            let len = chunk.len().min((size - checked) as usize);
+            flash.read(self.offset + position + checked, &mut chunk[..len])?;
+            if chunk[..len].iter().any(|&b| b != 0xff) {
+                return Ok(false);
+            }
+            checked += len as u32;
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,150 @@
+//! Transports carrying [`Message`]s between the halves.
+//!
+//! [`UartTransport`] frames the messages over a serial link, the I²C transports are in
+//! [`i2c`](crate::i2c). The hardware specific parts, like the DMA of the serial link, stay in the
+//! firmware.
+
+use crate::{
+    arbiter::{Arbiter, Turn},
+    dma::{TxBuffer, TxQueue},
+    link::{Decoder, LinkStats, Message, UartError},
+};
+use heapless::Deque;
+
+/// Messages received and not yet handled
+pub(crate) const INBOX_SIZE: usize = 16;
+
+/// Bytes sent on a single wire and not yet received back, a transmission waits for room
+const ECHO_SIZE: usize = 128;
+
+/// Link to the other half
+pub trait Transport {
+    type Error;
+
+    /// Sends or queues a message for the other half
+    fn send(&mut self, message: &Message) -> Result<(), Self::Error>;
+
+    /// Returns the oldest message received from the other half
+    fn receive(&mut self) -> Option<Message>;
+}
+
+/// The transmit queue of a [`UartTransport`] or an [`I2cMaster`](crate::i2c::I2cMaster) is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct QueueFull;
+
+/// Messages framed over a serial link, full or half-duplex depending on the [`Arbiter`].
+///
+/// On a single wire the half receives the bytes it sends. They are compared with the bytes sent,
+/// in order, and dropped. A byte that differs ends the echo and goes to the decoder: either it
+/// comes from the other half, or the bus was disturbed and the CRC drops the frame.
+#[derive(Clone, Debug)]
+pub struct UartTransport<const Q: usize> {
+    arbiter: Arbiter,
+    decoder: Decoder,
+    echo: Deque<u8, ECHO_SIZE>,
+    inbox: Deque<Message, INBOX_SIZE>,
+    queue: TxQueue<Q>,
+    stats: LinkStats,
+}
+
+impl<const Q: usize> UartTransport<Q> {
+    pub const fn new(arbiter: Arbiter) -> Self {
+        Self {
+            arbiter,
+            decoder: Decoder::new(),
+            echo: Deque::new(),
+            inbox: Deque::new(),
+            queue: TxQueue::new(),
+            stats: LinkStats::new(),
+        }
+    }
+
+    /// Traffic and error counters
+    pub fn stats(&self) -> LinkStats {
+        LinkStats {
+            rx_dropped: self.stats.rx_dropped.wrapping_add(self.decoder.invalid()),
+            crc_errors: self.decoder.crc_errors(),
+            ..self.stats
+        }
+    }
+
+    /// Counts an error reported by the UART
+    pub fn uart_error(&mut self, error: UartError) {
+        let count = match error {
+            UartError::Framing => &mut self.stats.framing_errors,
+            UartError::Noise => &mut self.stats.noise_errors,
+            UartError::Overrun => &mut self.stats.overruns,
+        };
+        *count = count.wrapping_add(1);
+    }
+
+    /// Decodes bytes received at `now_us`
+    pub fn received(&mut self, bytes: &[u8], now_us: u32) {
+        self.stats.rx_bytes = self.stats.rx_bytes.wrapping_add(bytes.len() as u32);
+        for &byte in bytes {
+            match self.echo.pop_front() {
+                Some(sent) if sent == byte => continue,
+                Some(_) => self.echo.clear(),
+                None => {}
+            }
+            let message = match self.decoder.push(byte) {
+                Some(message) => message,
+                None => continue,
+            };
+            self.stats.rx_frames = self.stats.rx_frames.wrapping_add(1);
+            if self.arbiter.receive(&message, now_us) && self.inbox.push_back(message).is_err() {
+                self.stats.rx_dropped = self.stats.rx_dropped.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Moves the bytes the half may send at `now_us` to `buffer`, returns how many were moved.
+    ///
+    /// Must be called only when the previous transmission is complete.
+    pub fn fill<const N: usize>(&mut self, buffer: &mut TxBuffer<N>, now_us: u32) -> usize {
+        let echoes = self.arbiter.echoes();
+        let limit = if echoes {
+            ECHO_SIZE - self.echo.len()
+        } else {
+            N
+        };
+        let token = Message::Token.encode();
+        // Waits for the echo of the previous transmissions to make room
+        if token.len() > limit {
+            return 0;
+        }
+        let len = match self.arbiter.poll(now_us, self.queue.is_empty()) {
+            Turn::Send => buffer.fill(&mut self.queue, limit),
+            Turn::PassToken => {
+                buffer.set(&token);
+                token.len()
+            }
+            Turn::Wait => 0,
+        };
+        if echoes {
+            for &byte in &buffer.as_slice()[..len] {
+                self.echo.push_back(byte).ok();
+            }
+        }
+        self.stats.tx_bytes = self.stats.tx_bytes.wrapping_add(len as u32);
+        len
+    }
+}
+
+impl<const Q: usize> Transport for UartTransport<Q> {
+    type Error = QueueFull;
+
+    fn send(&mut self, message: &Message) -> Result<(), QueueFull> {
+        if self.queue.push(&message.encode()) {
+            self.stats.tx_frames = self.stats.tx_frames.wrapping_add(1);
+            Ok(())
+        } else {
+            self.stats.tx_dropped = self.stats.tx_dropped.wrapping_add(1);
+            Err(QueueFull)
+        }
+    }
+
+    fn receive(&mut self) -> Option<Message> {
+        self.inbox.pop_front()
+    }
+}
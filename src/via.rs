@@ -0,0 +1,186 @@
+//! VIA configuration protocol over the raw HID interface.
+//!
+//! VIA requests use the same [`REPORT_SIZE`] bytes reports as the native
+//! [protocol](crate::protocol): the first byte is the command id and the response is written
+//! in place over the request. Unknown commands are answered with [`ID_UNHANDLED`].
+//!
+//! The firmware cannot play macros back, so it reports no macro and no macro buffer, which hides
+//! the macro tab of the VIA application.
+//!
+//! The matching keyboard definition for the VIA application is in `via/lets_split.json`.
+
+use crate::{
+    keymap::{Action, Keymap},
+    protocol::{Effect, REPORT_SIZE},
+};
+
+/// Protocol version implemented by the firmware
+pub const PROTOCOL_VERSION: u16 = 0x000c;
+
+pub const ID_GET_PROTOCOL_VERSION: u8 = 0x01;
+pub const ID_GET_KEYBOARD_VALUE: u8 = 0x02;
+pub const ID_SET_KEYBOARD_VALUE: u8 = 0x03;
+pub const ID_DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+pub const ID_DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+pub const ID_DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+pub const ID_EEPROM_RESET: u8 = 0x0a;
+pub const ID_BOOTLOADER_JUMP: u8 = 0x0b;
+pub const ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0c;
+pub const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0d;
+pub const ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0e;
+pub const ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0f;
+pub const ID_DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+pub const ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+pub const ID_DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+pub const ID_DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+pub const ID_UNHANDLED: u8 = 0xff;
+
+/// Keyboard value ids of [`ID_GET_KEYBOARD_VALUE`] and [`ID_SET_KEYBOARD_VALUE`]
+pub const ID_LAYOUT_OPTIONS: u8 = 0x02;
+pub const ID_FIRMWARE_VERSION: u8 = 0x04;
+pub const ID_DEVICE_INDICATION: u8 = 0x05;
+
+/// Largest chunk of a buffer transferred by a single request
+const CHUNK_SIZE: usize = REPORT_SIZE - 4;
+
+/// Returns true if `id` is a VIA command id rather than a native protocol one
+pub fn is_via_command(id: u8) -> bool {
+    matches!(id, 0x01..=0x13)
+}
+
+/// State exposed through VIA besides the keymap
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Via {
+    /// Layout options selected in the VIA application
+    pub layout_options: u32,
+}
+
+impl Via {
+    /// Answers the request in `report` in place and returns the work left to the firmware
+    pub fn handle<const R: usize, const C: usize, const L: usize>(
+        &mut self,
+        report: &mut [u8; REPORT_SIZE],
+        keymap: &mut Keymap<R, C, L>,
+        defaults: &Keymap<R, C, L>,
+    ) -> Effect {
+        match report[0] {
+            ID_GET_PROTOCOL_VERSION => {
+                report[1..3].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+            }
+            ID_GET_KEYBOARD_VALUE => match report[1] {
+                ID_LAYOUT_OPTIONS => {
+                    report[2..6].copy_from_slice(&self.layout_options.to_be_bytes());
+                }
+                ID_FIRMWARE_VERSION => {
+                    let v = crate::protocol::FIRMWARE_VERSION;
+                    report[2..6].copy_from_slice(&[0, v.major, v.minor, v.patch]);
+                }
+                _ => report[0] = ID_UNHANDLED,
+            },
+            ID_SET_KEYBOARD_VALUE => match report[1] {
+                ID_LAYOUT_OPTIONS => {
+                    self.layout_options =
+                        u32::from_be_bytes([report[2], report[3], report[4], report[5]]);
+                    return Effect::SettingsChanged;
+                }
+                ID_DEVICE_INDICATION => {}
+                _ => report[0] = ID_UNHANDLED,
+            },
+            ID_DYNAMIC_KEYMAP_GET_KEYCODE => {
+                let (layer, row, col) = position(report);
+                let keycode = keymap
+                    .get(layer, row, col)
+                    .map(Action::keycode)
+                    .unwrap_or_default();
+                report[4..6].copy_from_slice(&keycode.to_be_bytes());
+            }
+            ID_DYNAMIC_KEYMAP_SET_KEYCODE => {
+                let (layer, row, col) = position(report);
+                let keycode = u16::from_be_bytes([report[4], report[5]]);
+                if let Some(action) = Action::from_keycode(keycode) {
+                    if keymap.set(layer, row, col, action).is_ok() {
+                        return Effect::KeymapChanged;
+                    }
+                }
+            }
+            ID_DYNAMIC_KEYMAP_RESET => {
+                *keymap = *defaults;
+                return Effect::KeymapChanged;
+            }
+            ID_EEPROM_RESET => {
+                *keymap = *defaults;
+                *self = Self::default();
+                return Effect::KeymapChanged;
+            }
+            ID_BOOTLOADER_JUMP => return Effect::Bootloader,
+            ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT => report[1] = 0,
+            ID_DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => report[1..3].fill(0),
+            ID_DYNAMIC_KEYMAP_MACRO_RESET => {}
+            ID_DYNAMIC_KEYMAP_GET_LAYER_COUNT => report[1] = L as u8,
+            ID_DYNAMIC_KEYMAP_GET_BUFFER => {
+                if let Some((offset, size)) = chunk(report, L * R * C * 2) {
+                    for i in offset..offset + size {
+                        report[4 + i - offset] = keycode_bytes(keymap, i / 2)[i % 2];
+                    }
+                }
+            }
+            ID_DYNAMIC_KEYMAP_SET_BUFFER => {
+                if let Some((offset, size)) = chunk(report, L * R * C * 2) {
+                    let mut i = offset;
+                    while i < offset + size {
+                        let index = i / 2;
+                        let mut bytes = keycode_bytes(keymap, index);
+                        while i < offset + size && i / 2 == index {
+                            bytes[i % 2] = report[4 + i - offset];
+                            i += 1;
+                        }
+                        set_keycode(keymap, index, u16::from_be_bytes(bytes));
+                    }
+                    return Effect::KeymapChanged;
+                }
+            }
+            _ => report[0] = ID_UNHANDLED,
+        }
+        Effect::None
+    }
+}
+
+/// Layer, row and column arguments of a keycode request
+fn position(report: &[u8; REPORT_SIZE]) -> (usize, usize, usize) {
+    (report[1].into(), report[2].into(), report[3].into())
+}
+
+/// Offset and size of a buffer request, if it fits in a buffer of `len` bytes
+fn chunk(report: &[u8; REPORT_SIZE], len: usize) -> Option<(usize, usize)> {
+    let offset = u16::from_be_bytes([report[1], report[2]]).into();
+    let size = usize::from(report[3]);
+    (size <= CHUNK_SIZE && offset + size <= len).then_some((offset, size))
+}
+
+/// Splits a keymap index in layer, row and column
+fn unflatten<const R: usize, const C: usize>(index: usize) -> (usize, usize, usize) {
+    (index / (R * C), index / C % R, index % C)
+}
+
+fn keycode_bytes<const R: usize, const C: usize, const L: usize>(
+    keymap: &Keymap<R, C, L>,
+    index: usize,
+) -> [u8; 2] {
+    let (layer, row, col) = unflatten::<R, C>(index);
+    keymap
+        .get(layer, row, col)
+        .map(Action::keycode)
+        .unwrap_or_default()
+        .to_be_bytes()
+}
+
+fn set_keycode<const R: usize, const C: usize, const L: usize>(
+    keymap: &mut Keymap<R, C, L>,
+    index: usize,
+    keycode: u16,
+) {
+    let (layer, row, col) = unflatten::<R, C>(index);
+    if let Some(action) = Action::from_keycode(keycode) {
+        keymap.set(layer, row, col, action).ok();
+    }
+}
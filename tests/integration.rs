@@ -7,10 +7,1051 @@ use lets_split as _; // memory layout + panic handler
 // feature)
 #[defmt_test::tests]
 mod tests {
-    use defmt::assert;
+    use core::{cell::RefCell, convert::Infallible};
+    use defmt::{assert, assert_eq};
+    use embedded_hal::blocking::i2c::{Write, WriteRead};
+    use lets_split::{
+        arbiter::{Arbiter, ArbiterConfig, Turn},
+        bounce::{self, BounceConfig, BounceStats},
+        clock::ClockOffset,
+        diagnostic::{self, DiagnosticConfig, Diagnostics, Finding},
+        dma::{TxBuffer, TxQueue},
+        encoder::{Direction, EncoderEvent, EncoderMap, Quadrature},
+        gesture::{Gesture, GestureConfig, GestureDetector},
+        health::{HealthConfig, KeySet, LinkMonitor, LinkState},
+        heatmap::{self, Heatmap},
+        i2c::{self, I2cBus, I2cLink, I2cMaster, I2cSlave},
+        joystick::{self, Joystick, JoystickConfig, Motion},
+        keymap::{Action, Keymap, KeymapError, LayerState},
+        led::{Indicators, LedConfig, Pattern},
+        link::{Decoder, Message},
+        merge::EventMerger,
+        position::{HalfMap, KeyEvent, KeyPosition, MatrixEvent, MatrixPosition},
+        protocol::{self, Command, Effect, Response, Status, REPORT_SIZE},
+        report::ReportQueue,
+        settings::Settings,
+        side::Side,
+        transport::{QueueFull, Transport, UartTransport},
+        via::{self, Via},
+    };
+
+    const KEYMAP: Keymap<1, 2, 2> = Keymap::new([
+        [[Action::Key(0x04), Action::MomentaryLayer(1)]],
+        [[Action::Key(0x1e), Action::Transparent]],
+    ]);
+
+    /// I²C bus with a single slave
+    struct MockBus<'a>(&'a RefCell<I2cSlave<2, 3>>);
+
+    impl Write for MockBus<'_> {
+        type Error = Infallible;
+
+        fn write(&mut self, _address: u8, bytes: &[u8]) -> Result<(), Infallible> {
+            let mut slave = self.0.borrow_mut();
+            slave.start(true);
+            bytes.iter().for_each(|&byte| slave.write(byte));
+            Ok(())
+        }
+    }
+
+    impl WriteRead for MockBus<'_> {
+        type Error = Infallible;
+
+        fn write_read(
+            &mut self,
+            address: u8,
+            bytes: &[u8],
+            buffer: &mut [u8],
+        ) -> Result<(), Infallible> {
+            self.write(address, bytes)?;
+            let mut slave = self.0.borrow_mut();
+            slave.start(false);
+            buffer.iter_mut().for_each(|byte| *byte = slave.read());
+            Ok(())
+        }
+    }
 
     #[test]
     fn it_works() {
         assert!(true)
     }
+
+    #[test]
+    fn report_queue_retries_busy_endpoint() {
+        let mut reports: ReportQueue<u8, 2> = ReportQueue::new(None);
+        reports.push(1);
+
+        assert!(!reports.send(0, |_| Ok::<_, ()>(0)));
+        assert!(!reports.send(0, |_| Err(())));
+        assert_eq!(reports.len(), 1);
+
+        assert!(reports.send(0, |r| Ok::<_, ()>(usize::from(*r))));
+        assert!(reports.is_empty());
+        assert!(!reports.send(10_000, |_| Ok::<_, ()>(1)));
+    }
+
+    #[test]
+    fn report_queue_replaces_newest_when_full() {
+        let mut reports: ReportQueue<u8, 2> = ReportQueue::new(None);
+        reports.push(1);
+        reports.push(2);
+        reports.push(3);
+
+        let mut sent = [0; 2];
+        for slot in sent.iter_mut() {
+            reports.send(0, |r| {
+                *slot = *r;
+                Ok::<_, ()>(1)
+            });
+        }
+        assert_eq!(sent, [1, 3]);
+    }
+
+    #[test]
+    fn report_queue_repeats_after_idle() {
+        let mut reports: ReportQueue<u8, 2> = ReportQueue::new(Some(500));
+        reports.push(7);
+        assert!(reports.send(100, |_| Ok::<_, ()>(1)));
+
+        assert!(!reports.send(599, |_| Ok::<_, ()>(1)));
+        let mut repeated = 0;
+        assert!(reports.send(600, |r| {
+            repeated = *r;
+            Ok::<_, ()>(1)
+        }));
+        assert_eq!(repeated, 7);
+    }
+
+    #[test]
+    fn action_keycode_roundtrip() {
+        for action in [
+            Action::No,
+            Action::Transparent,
+            Action::Key(0x04),
+            Action::Key(0xe7),
+            Action::MomentaryLayer(3),
+            Action::Bootloader,
+            Action::Reset,
+        ] {
+            assert_eq!(Action::from_keycode(action.keycode()), Some(action));
+        }
+        assert_eq!(Action::from_keycode(0x7c02), None);
+    }
+
+    #[test]
+    fn keymap_rejects_invalid_edits() {
+        let mut keymap = KEYMAP;
+        assert_eq!(keymap.get(2, 0, 0), Err(KeymapError::OutOfRange));
+        assert_eq!(
+            keymap.set(1, 0, 0, Action::MomentaryLayer(1)),
+            Err(KeymapError::Unsupported)
+        );
+        assert_eq!(
+            keymap.set(0, 0, 0, Action::MomentaryLayer(2)),
+            Err(KeymapError::Unsupported)
+        );
+        assert_eq!(keymap.set(1, 0, 1, Action::Key(0x1f)), Ok(()));
+        assert_eq!(keymap.get(1, 0, 1), Ok(Action::Key(0x1f)));
+    }
+
+    fn key(row: u8, col: u8, pressed: bool) -> KeyEvent {
+        KeyEvent {
+            position: KeyPosition::new(row, col),
+            pressed,
+        }
+    }
+
+    #[test]
+    fn layer_state_finds_special_actions() {
+        let mut keymap = KEYMAP;
+        keymap.set(1, 0, 0, Action::Bootloader).unwrap();
+        let mut layers = LayerState::new();
+
+        assert_eq!(layers.update(&keymap, key(0, 0, true)), Action::Key(0x04));
+        assert_eq!(
+            layers.update(&keymap, key(0, 1, true)),
+            Action::MomentaryLayer(1)
+        );
+        assert_eq!(layers.active(), 1);
+        assert_eq!(layers.update(&keymap, key(0, 0, true)), Action::Bootloader);
+        assert_eq!(keymap.resolve(1, 0, 0, 1), Ok(Action::MomentaryLayer(1)));
+
+        layers.update(&keymap, key(0, 1, false));
+        assert_eq!(layers.active(), 0);
+
+        assert_eq!(layers.cycle_default(), 1);
+        assert_eq!(layers.update(&keymap, key(0, 0, true)), Action::Bootloader);
+        assert_eq!(
+            layers.update(&keymap, key(0, 1, true)),
+            Action::MomentaryLayer(1)
+        );
+        assert_eq!(layers.cycle_default(), 0);
+    }
+
+    #[test]
+    fn encoder_reports_rotations_per_detent() {
+        let mut encoder = Quadrature::new(4);
+        // Clockwise the A output changes first, the first sample sets the code
+        let clockwise = [(true, true), (false, true), (false, false), (true, false)];
+        assert_eq!(encoder.update(true, true), None);
+        for &(a, b) in &clockwise[1..] {
+            assert_eq!(encoder.update(a, b), None);
+        }
+        // Bounces of a contact move back and forth
+        assert_eq!(encoder.update(false, false), None);
+        assert_eq!(encoder.update(true, false), None);
+        assert_eq!(encoder.update(true, true), Some(Direction::Clockwise));
+
+        // Both outputs changing at once are ignored
+        assert_eq!(encoder.update(false, false), None);
+        assert_eq!(encoder.update(true, true), None);
+        for &(a, b) in clockwise[1..].iter().rev() {
+            assert_eq!(encoder.update(a, b), None);
+        }
+        assert_eq!(
+            encoder.update(true, true),
+            Some(Direction::CounterClockwise)
+        );
+
+        let map = EncoderMap::<2, 2>::new([
+            [[Action::Key(0x80), Action::Key(0x81)], [Action::No; 2]],
+            [[Action::Transparent, Action::Key(0x4b)], [Action::Reset; 2]],
+        ]);
+        let turn = |index, direction| EncoderEvent { index, direction };
+        assert_eq!(
+            map.resolve(1, 0, turn(0, Direction::Clockwise)),
+            Action::Key(0x80)
+        );
+        assert_eq!(
+            map.resolve(1, 0, turn(0, Direction::CounterClockwise)),
+            Action::Key(0x4b)
+        );
+        assert_eq!(map.resolve(0, 0, turn(1, Direction::Clockwise)), Action::No);
+        assert_eq!(map.resolve(1, 0, turn(2, Direction::Clockwise)), Action::No);
+    }
+
+    #[test]
+    fn gestures_follow_timing_thresholds() {
+        let mut button = GestureDetector::new(GestureConfig {
+            debounce_ms: 20,
+            long_press_ms: 1000,
+            double_press_ms: 300,
+        });
+
+        // Short press, reported after the double press window
+        assert_eq!(button.update(0, Some(true)), None);
+        assert_eq!(button.update(100, Some(false)), None);
+        assert_eq!(button.update(400, None), None);
+        assert_eq!(button.update(401, None), Some(Gesture::Short));
+
+        // Double press
+        button.update(1000, Some(true));
+        button.update(1100, Some(false));
+        assert_eq!(button.update(1300, Some(true)), None);
+        assert_eq!(button.update(1400, Some(false)), None);
+        assert_eq!(button.update(1420, None), Some(Gesture::Double));
+
+        // Long press, reported while held
+        button.update(2000, Some(true));
+        assert_eq!(button.update(2999, None), None);
+        assert_eq!(button.update(3000, None), Some(Gesture::Long));
+        assert_eq!(button.update(3500, Some(false)), None);
+        assert_eq!(button.update(5000, None), None);
+
+        // A bouncing press is a single short press
+        button.update(6000, Some(true));
+        button.update(6002, Some(false));
+        button.update(6004, Some(true));
+        assert_eq!(button.update(6010, None), None);
+        assert_eq!(button.update(6050, Some(false)), None);
+        assert_eq!(button.update(6052, Some(true)), None);
+        assert_eq!(button.update(6054, Some(false)), None);
+        assert_eq!(button.update(6300, None), None);
+        assert_eq!(button.update(6400, None), Some(Gesture::Short));
+        assert!(button.is_idle());
+    }
+
+    #[test]
+    fn protocol_set_and_reset_keycode() {
+        let mut settings: Settings<1, 2, 2> = Settings::new(KEYMAP);
+        let set = Command::SetKeycode {
+            layer: 1,
+            row: 0,
+            col: 0,
+            keycode: 0x1f,
+        };
+        let get = Command::GetKeycode {
+            layer: 1,
+            row: 0,
+            col: 0,
+        };
+
+        let request = set.encode();
+        let decoded = Command::decode(&request).unwrap();
+        assert_eq!(decoded, set);
+        assert_eq!(decoded.apply(&mut settings, &KEYMAP), Ok(Response::Done));
+        assert_eq!(
+            get.apply(&mut settings, &KEYMAP),
+            Ok(Response::Keycode(0x1f))
+        );
+
+        Command::ResetKeymap.apply(&mut settings, &KEYMAP).unwrap();
+        let result = get.apply(&mut settings, &KEYMAP);
+        let report = protocol::encode_response(get.id() as u8, result);
+        assert_eq!(
+            protocol::decode_response(&get, &report),
+            Ok(Response::Keycode(0x1e))
+        );
+    }
+
+    #[test]
+    fn protocol_reports_errors() {
+        let mut report = [0; REPORT_SIZE];
+        report[0] = 0x42;
+        assert_eq!(Command::decode(&report), Err(Status::UnknownCommand));
+
+        let mut settings: Settings<1, 2, 2> = Settings::new(KEYMAP);
+        let get = Command::GetKeycode {
+            layer: 0,
+            row: 1,
+            col: 0,
+        };
+        assert_eq!(
+            get.apply(&mut settings, &KEYMAP),
+            Err(Status::InvalidArgument)
+        );
+        assert_eq!(
+            Command::GetLayerCount.apply(&mut settings, &KEYMAP),
+            Ok(Response::LayerCount(2))
+        );
+    }
+
+    #[test]
+    fn bounce_stats_flag_long_bounces() {
+        let mut stats = BounceStats::<4, 12>::new(BounceConfig::default());
+        assert_eq!(stats.update(key(1, 2, true), 0), None);
+        assert!(stats.iter().next().is_none());
+
+        stats.set_enabled(true);
+        for &(pressed, time_us) in &[(true, 10_000), (false, 11_000), (true, 12_000)] {
+            assert_eq!(stats.update(key(1, 2, pressed), time_us), None);
+        }
+        assert_eq!(stats.update(key(1, 2, false), 14_000), None);
+        // Still bouncing 5.5 ms after the first transition
+        assert_eq!(stats.update(key(1, 2, true), 15_500), Some(5_500));
+        assert_eq!(stats.update(key(1, 2, false), 60_000), None);
+        assert_eq!(stats.update(key(0, 5, true), 60_000), None);
+
+        let (position, key_stats) = stats.iter().next().unwrap();
+        assert_eq!(position, KeyPosition::new(0, 5));
+        assert_eq!(key_stats.presses, 1);
+        let key_stats = *stats.get(KeyPosition::new(1, 2)).unwrap();
+        assert_eq!(
+            (key_stats.presses, key_stats.releases, key_stats.bounces),
+            (3, 3, 4)
+        );
+        assert!(key_stats.flagged);
+        let report = key_stats.encode(KeyPosition::new(1, 2));
+        assert_eq!(report[..4], [bounce::REPORT_ID, 1, 2, 1]);
+        assert_eq!(report[4..10], [0, 3, 0, 3, 0, 4]);
+        assert_eq!(report[10..14], 5_500u32.to_be_bytes());
+
+        let request = Command::SetBounceStats { enabled: false }.encode();
+        let mut settings: Settings<1, 2, 2> = Settings::new(KEYMAP);
+        let (_, effect) = protocol::handle_request(&request, &mut settings, &KEYMAP);
+        assert_eq!(effect, Effect::BounceStats { enabled: false });
+        stats.set_enabled(false);
+        assert_eq!(stats.update(key(0, 5, false), 70_000), None);
+        assert_eq!(stats.get(KeyPosition::new(0, 5)).unwrap().releases, 0);
+    }
+
+    #[test]
+    fn heatmap_counts_presses_once_enabled() {
+        let mut heatmap = Heatmap::<2, 4, 12>::new();
+        heatmap.record(0, KeyPosition::new(1, 2));
+        assert_eq!(heatmap.presses(0, KeyPosition::new(1, 2)), Some(0));
+        assert!(!heatmap.take_dirty());
+
+        let mut request = [0; REPORT_SIZE];
+        request[..2].copy_from_slice(&[heatmap::ID_SET_HEATMAP_ENABLED, 1]);
+        assert!(heatmap::is_heatmap_command(request[0]));
+        let (response, effect) = heatmap.handle_request(&request);
+        assert_eq!(
+            response[..2],
+            [heatmap::ID_SET_HEATMAP_ENABLED, Status::Ok as u8]
+        );
+        assert_eq!(effect, Effect::HeatmapChanged);
+        heatmap.record(0, KeyPosition::new(1, 2));
+        heatmap.record(1, KeyPosition::new(1, 9));
+        heatmap.record(1, KeyPosition::new(1, 9));
+        heatmap.record(2, KeyPosition::new(1, 9));
+        assert!(heatmap.take_dirty());
+
+        request[..4].copy_from_slice(&[heatmap::ID_GET_PRESSES, 1, 1, 8]);
+        let (response, effect) = heatmap.handle_request(&request);
+        assert_eq!(effect, Effect::None);
+        assert_eq!(response[1], Status::Ok as u8);
+        assert_eq!(response[2..10], [0, 0, 0, 0, 0, 0, 0, 2]);
+        request[3] = 12;
+        let (response, _) = heatmap.handle_request(&request);
+        assert_eq!(response[1], Status::InvalidArgument as u8);
+
+        request[0] = heatmap::ID_SAVE_HEATMAP;
+        assert!(heatmap::is_heatmap_command(request[0]));
+        let (response, effect) = heatmap.handle_request(&request);
+        assert_eq!(response[..2], [heatmap::ID_SAVE_HEATMAP, Status::Ok as u8]);
+        assert_eq!(effect, Effect::HeatmapChanged);
+
+        let mut buf = [0; Heatmap::<2, 4, 12>::SIZE];
+        let restored = Heatmap::<2, 4, 12>::decode(heatmap.encode(&mut buf)).unwrap();
+        assert!(restored.is_enabled());
+        assert_eq!(restored.presses(1, KeyPosition::new(1, 9)), Some(2));
+        assert_eq!(Heatmap::<1, 4, 12>::decode(&buf), None);
+
+        heatmap.reset();
+        assert_eq!(heatmap.presses(0, KeyPosition::new(1, 2)), Some(0));
+        assert!(heatmap.is_enabled() && heatmap.take_dirty());
+    }
+
+    #[test]
+    fn via_keymap_buffer_roundtrip() {
+        let mut settings: Settings<1, 2, 2> = Settings::new(KEYMAP);
+
+        let mut request = [0; REPORT_SIZE];
+        request[..8].copy_from_slice(&[
+            via::ID_DYNAMIC_KEYMAP_SET_BUFFER,
+            0,
+            4,
+            4,
+            0x00,
+            0x1f,
+            0x00,
+            0x01,
+        ]);
+        let (_, effect) = protocol::handle_request(&request, &mut settings, &KEYMAP);
+        assert_eq!(effect, Effect::KeymapChanged);
+        assert_eq!(settings.keymap.get(1, 0, 0), Ok(Action::Key(0x1f)));
+
+        request[0] = via::ID_DYNAMIC_KEYMAP_GET_BUFFER;
+        request[4..8].fill(0);
+        let (response, _) = protocol::handle_request(&request, &mut settings, &KEYMAP);
+        assert_eq!(response[4..8], [0x00, 0x1f, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn via_reports_no_macros() {
+        let mut keymap = KEYMAP;
+        let mut state = Via::default();
+
+        let mut request = [0; REPORT_SIZE];
+        request[..2].copy_from_slice(&[via::ID_DYNAMIC_KEYMAP_MACRO_GET_COUNT, 0xff]);
+        state.handle(&mut request, &mut keymap, &KEYMAP);
+        assert_eq!(request[1], 0);
+
+        request[..5].copy_from_slice(&[via::ID_DYNAMIC_KEYMAP_MACRO_SET_BUFFER, 0, 0, 1, b'a']);
+        assert_eq!(
+            state.handle(&mut request, &mut keymap, &KEYMAP),
+            Effect::None
+        );
+        assert_eq!(request[0], via::ID_UNHANDLED);
+
+        request[0] = 0x42;
+        assert_eq!(
+            state.handle(&mut request, &mut keymap, &KEYMAP),
+            Effect::None
+        );
+        assert_eq!(request[0], via::ID_UNHANDLED);
+    }
+
+    #[test]
+    fn protocol_stores_side() {
+        let mut settings: Settings<1, 2, 2> = Settings::new(KEYMAP);
+        let set = Command::SetSide { side: Side::Right };
+        let request = set.encode();
+        assert_eq!(Command::decode(&request), Ok(set));
+
+        let (_, effect) = protocol::handle_request(&request, &mut settings, &KEYMAP);
+        assert_eq!(effect, Effect::SettingsChanged);
+        let (report, _) =
+            protocol::handle_request(&Command::GetSide.encode(), &mut settings, &KEYMAP);
+        assert_eq!(
+            protocol::decode_response(&Command::GetSide, &report),
+            Ok(Response::Side(Some(Side::Right)))
+        );
+
+        assert_eq!(Side::resolve(false, settings.side), Side::Right);
+        assert_eq!(Side::resolve(true, Some(Side::Left)), Side::Right);
+        assert_eq!(Side::resolve(false, None), Side::Left);
+    }
+
+    #[test]
+    fn joystick_moves_beyond_dead_zone() {
+        let config = JoystickConfig {
+            dead_zone: 100,
+            range: 1_100,
+            max_speed: 10,
+            acceleration: 1_000,
+            invert_x: false,
+            invert_y: true,
+        };
+        let mut stick = Joystick::new(config);
+        for _ in 0..joystick::CALIBRATION_SAMPLES {
+            assert_eq!(stick.update(2_000, 2_100), None);
+        }
+        assert!(stick.is_calibrated());
+
+        // Within the dead zone
+        assert_eq!(stick.update(2_090, 2_010), None);
+        // Full deflection, the Y axis is inverted
+        assert_eq!(stick.update(3_100, 3_200), Some(Motion { dx: 10, dy: -10 }));
+        // A quarter of a full deflection is a sixteenth of the speed, carried over
+        assert_eq!(stick.update(1_650, 2_100), None);
+        assert_eq!(stick.update(1_650, 2_100), Some(Motion { dx: -1, dy: 0 }));
+
+        // The range widens with the stick going further
+        assert_eq!(stick.update(2_000, 2_100), None);
+        assert_eq!(stick.update(3_600, 2_100), Some(Motion { dx: 10, dy: 0 }));
+        assert_eq!(stick.update(3_100, 2_100), Some(Motion { dx: 4, dy: 0 }));
+
+        let report = Motion { dx: -128, dy: 3 }.report();
+        assert_eq!(report, [0, 0x81, 3]);
+    }
+
+    #[test]
+    fn link_frames_roundtrip() {
+        let mut decoder = Decoder::new();
+        let hello = Message::Hello { side: Side::Left };
+        let event = Message::Event {
+            position: KeyPosition::new(3, 11),
+            pressed: true,
+            time_us: 0x0102_0304,
+        };
+        let pong = Message::Pong {
+            origin_us: u32::MAX,
+            remote_us: 7,
+        };
+        let encoder = Message::Encoder {
+            index: 1,
+            direction: Direction::CounterClockwise,
+        };
+        let motion = Message::Motion(Motion { dx: -127, dy: 5 });
+        let heartbeat = Message::Heartbeat {
+            pressed: 1 << 47 | 1,
+        };
+
+        // Garbage and a corrupted frame before valid frames
+        let mut corrupted = event.encode();
+        corrupted[4] ^= 0x01;
+        let mut received = heapless::Vec::<Message, 8>::new();
+        for &byte in [0x00, 0x42]
+            .iter()
+            .chain(corrupted.iter())
+            .chain(hello.encode().iter())
+            .chain(event.encode().iter())
+            .chain(pong.encode().iter())
+            .chain(encoder.encode().iter())
+            .chain(motion.encode().iter())
+            .chain(heartbeat.encode().iter())
+        {
+            if let Some(message) = decoder.push(byte) {
+                received.push(message).unwrap();
+            }
+        }
+        assert_eq!(received, [hello, event, pong, encoder, motion, heartbeat]);
+        assert_eq!((decoder.crc_errors(), decoder.invalid()), (1, 0));
+    }
+
+    #[test]
+    fn tx_queue_keeps_frames_whole() {
+        let mut queue = TxQueue::<8>::new();
+        let mut buf = [0; 4];
+        assert!(queue.push(&[1, 2, 3, 4, 5]));
+        assert!(!queue.push(&[6, 7, 8, 9]));
+        assert_eq!(queue.pop_into(&mut buf), 4);
+        assert_eq!(buf, [1, 2, 3, 4]);
+
+        // Wraps around the end of the ring
+        assert!(queue.push(&[6, 7, 8, 9]));
+        assert_eq!(queue.pop_into(&mut buf), 4);
+        assert_eq!(buf, [5, 6, 7, 8]);
+        assert_eq!(queue.pop_into(&mut buf), 1);
+        assert_eq!(buf[0], 9);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn arbiter_passes_token_between_halves() {
+        let config = ArbiterConfig::default();
+        let mut primary = Arbiter::half_duplex(true, config);
+        let mut secondary = Arbiter::half_duplex(false, config);
+        let event = Message::Event {
+            position: KeyPosition::new(0, 0),
+            pressed: true,
+            time_us: 0,
+        };
+
+        // The primary keeps the token for the poll period
+        assert_eq!(secondary.poll(100, false), Turn::Wait);
+        assert_eq!(primary.poll(100, false), Turn::Send);
+        assert_eq!(primary.poll(200, true), Turn::Send);
+        assert_eq!(primary.poll(500, true), Turn::PassToken);
+        assert_eq!(primary.poll(600, false), Turn::Wait);
+
+        // The secondary answers right away
+        assert!(!secondary.receive(&Message::Token, 600));
+        assert_eq!(secondary.poll(610, false), Turn::Send);
+        assert_eq!(secondary.poll(620, true), Turn::PassToken);
+        assert_eq!(secondary.poll(625, false), Turn::Wait);
+        assert!(primary.receive(&event, 630));
+        assert!(!primary.receive(&Message::Token, 640));
+        assert_eq!(primary.poll(650, true), Turn::Send);
+
+        // The primary takes back a token that is not returned
+        assert_eq!(primary.poll(1_200, true), Turn::PassToken);
+        assert_eq!(primary.poll(6_200, true), Turn::Wait);
+        assert_eq!(primary.poll(6_201, false), Turn::Send);
+
+        // Frames still sent by the other half are kept, its late token is ignored
+        assert!(primary.receive(&event, 6_300));
+        assert!(!primary.receive(&Message::Token, 6_400));
+        assert_eq!(primary.poll(6_500, false), Turn::Send);
+
+        let mut full = Arbiter::full_duplex();
+        assert_eq!(full.poll(0, true), Turn::Send);
+        assert!(full.receive(&event, 0));
+    }
+
+    #[test]
+    fn uart_transport_drops_echo() {
+        static mut BUF: [u8; 16] = [0; 16];
+        let mut buffer = TxBuffer::new(unsafe { &mut *core::ptr::addr_of_mut!(BUF) });
+        let mut primary =
+            UartTransport::<8>::new(Arbiter::half_duplex(true, ArbiterConfig::default()));
+        let hello = Message::Hello { side: Side::Left };
+
+        primary.send(&hello).unwrap();
+        assert_eq!(primary.send(&hello), Err(QueueFull));
+        assert_eq!(primary.fill(&mut buffer, 0), 5);
+        // Echo of our frame, then the answer of the other half
+        let answer = Message::Hello { side: Side::Right };
+        primary.received(&hello.encode(), 10);
+        assert_eq!(primary.fill(&mut buffer, 600), 4);
+        primary.received(&Message::Token.encode(), 610);
+        primary.received(&answer.encode(), 620);
+        primary.received(&Message::Token.encode(), 630);
+        assert_eq!(primary.receive(), Some(answer));
+        assert_eq!(primary.receive(), None);
+
+        // A corrupted echo does not hide the frames of the other half
+        primary.send(&hello).unwrap();
+        assert_eq!(primary.fill(&mut buffer, 700), 5);
+        let mut echo = hello.encode();
+        echo[4] ^= 0x01;
+        primary.received(&echo, 710);
+        primary.received(&answer.encode(), 720);
+        assert_eq!(primary.receive(), Some(answer));
+
+        let stats = primary.stats();
+        assert_eq!(
+            (stats.tx_frames, stats.tx_dropped, stats.tx_bytes),
+            (2, 1, 14)
+        );
+        assert_eq!((stats.rx_frames, stats.rx_bytes), (3, 28));
+    }
+
+    #[test]
+    fn i2c_master_polls_slave_matrix() {
+        let slave = RefCell::new(I2cSlave::new());
+        let mut bus = I2cBus::new(MockBus(&slave), i2c::DEFAULT_ADDRESS);
+        let mut master = I2cMaster::<2, 3>::new();
+        master.update(&bus.read().unwrap(), 0);
+        assert_eq!(master.receive(), Some(Message::Heartbeat { pressed: 0 }));
+        assert_eq!(master.receive(), None);
+
+        let mut slave_half = slave.borrow_mut();
+        slave_half
+            .send(&Message::Hello { side: Side::Right })
+            .unwrap();
+        let press = |col, pressed| Message::Event {
+            position: KeyPosition::new(1, col),
+            pressed,
+            time_us: 0,
+        };
+        slave_half.send(&press(2, true)).unwrap();
+        slave_half.send(&press(0, true)).unwrap();
+        drop(slave_half);
+        master.update(&bus.read().unwrap(), 100);
+        let received = core::iter::from_fn(|| master.receive()).collect::<heapless::Vec<_, 5>>();
+        assert_eq!(
+            received,
+            [
+                Message::Hello { side: Side::Right },
+                Message::Event {
+                    position: KeyPosition::new(1, 0),
+                    pressed: true,
+                    time_us: 100
+                },
+                Message::Event {
+                    position: KeyPosition::new(1, 2),
+                    pressed: true,
+                    time_us: 100
+                },
+                Message::Heartbeat {
+                    pressed: 0b0010_1000
+                },
+            ]
+        );
+
+        // Queued until written outside of the master
+        master.send(&Message::Hello { side: Side::Left }).unwrap();
+        assert_eq!(slave.borrow_mut().receive(), None);
+        bus.write(&master.next_write().unwrap()).unwrap();
+        assert_eq!(master.next_write(), None);
+        assert_eq!(
+            slave.borrow_mut().receive(),
+            Some(Message::Hello { side: Side::Left })
+        );
+
+        // Either end behind the same transport
+        let mut link = I2cLink::Master(master);
+        link.update(&bus.read().unwrap(), 200);
+        assert_eq!(
+            link.receive(),
+            Some(Message::Heartbeat {
+                pressed: 0b0010_1000
+            })
+        );
+        assert!(link.slave_mut().is_none());
+        let mut link = I2cLink::<2, 3>::Slave(I2cSlave::new());
+        link.send(&press(1, true)).unwrap();
+        assert_eq!(link.next_write(), None);
+        let slave = link.slave_mut().unwrap();
+        slave.start(true);
+        slave.write(i2c::REG_MATRIX);
+        slave.start(false);
+        assert_eq!(slave.read(), 0b0001_0000);
+    }
+
+    #[test]
+    fn i2c_master_polls_slave_encoders() {
+        let slave = RefCell::new(I2cSlave::new());
+        let mut bus = I2cBus::new(MockBus(&slave), i2c::DEFAULT_ADDRESS);
+        let mut master = I2cMaster::<2, 3>::new();
+        let step = |index, direction| Message::Encoder { index, direction };
+        let mut slave_half = slave.borrow_mut();
+        slave_half.send(&step(1, Direction::Clockwise)).unwrap();
+        slave_half.send(&step(1, Direction::Clockwise)).unwrap();
+        slave_half
+            .send(&step(1, Direction::CounterClockwise))
+            .unwrap();
+        // Beyond the encoder registers
+        slave_half.send(&step(2, Direction::Clockwise)).unwrap();
+        drop(slave_half);
+        master.update(&bus.read().unwrap(), 0);
+        assert_eq!(master.receive(), Some(step(1, Direction::Clockwise)));
+        assert_eq!(master.receive(), Some(Message::Heartbeat { pressed: 0 }));
+        assert_eq!(master.receive(), None);
+
+        // Read steps are cleared, the ones that do not fit in the inbox come with the next poll
+        for _ in 0..20 {
+            slave
+                .borrow_mut()
+                .send(&step(0, Direction::CounterClockwise))
+                .unwrap();
+        }
+        master.update(&bus.read().unwrap(), 100);
+        let received = core::iter::from_fn(|| master.receive()).count();
+        assert_eq!(received, 16);
+        master.update(&bus.read().unwrap(), 200);
+        for _ in 0..4 {
+            assert_eq!(master.receive(), Some(step(0, Direction::CounterClockwise)));
+        }
+        assert_eq!(master.receive(), Some(Message::Heartbeat { pressed: 0 }));
+        assert_eq!(master.receive(), None);
+    }
+
+    #[test]
+    fn i2c_master_polls_slave_motion() {
+        let slave = RefCell::new(I2cSlave::new());
+        let mut bus = I2cBus::new(MockBus(&slave), i2c::DEFAULT_ADDRESS);
+        let mut master = I2cMaster::<2, 3>::new();
+        let motion = |dx, dy| Message::Motion(Motion { dx, dy });
+        slave.borrow_mut().send(&motion(100, -3)).unwrap();
+        slave.borrow_mut().send(&motion(100, 5)).unwrap();
+        master.update(&bus.read().unwrap(), 0);
+        // Summed until read, saturating
+        assert_eq!(master.receive(), Some(motion(127, 2)));
+        assert_eq!(master.receive(), Some(Message::Heartbeat { pressed: 0 }));
+
+        // Read movements are cleared
+        master.update(&bus.read().unwrap(), 100);
+        assert_eq!(master.receive(), Some(Message::Heartbeat { pressed: 0 }));
+        assert_eq!(master.receive(), None);
+    }
+
+    #[test]
+    fn clock_offset_prefers_short_round_trips() {
+        let mut clock = ClockOffset::new();
+        assert_eq!(clock.to_local(5_000, 1_000), 1_000);
+
+        // Remote clock 10 ms ahead, 2 ms round trip
+        clock.update(1_000, 12_000, 3_000);
+        assert_eq!(clock.offset_us(), Some(10_000));
+        // A slow answer does not move the estimate
+        clock.update(4_000, 14_500, 8_000);
+        assert_eq!(clock.offset_us(), Some(10_000));
+        clock.update(9_000, 19_600, 10_000);
+        assert_eq!(clock.offset_us(), Some(10_100));
+
+        assert_eq!(clock.to_local(20_100, 12_000), 10_000);
+        // Never later than now
+        assert_eq!(clock.to_local(30_000, 12_000), 12_000);
+        // Across the wrap of the clocks
+        assert_eq!(clock.to_local(50, 0), 50u32.wrapping_sub(10_100));
+    }
+
+    #[test]
+    fn merger_releases_events_in_time_order() {
+        let mut merger = EventMerger::<char, 3>::new(1_000);
+        merger.push(u32::MAX - 100, 'a').unwrap();
+        merger.push(200, 'c').unwrap();
+        merger.push(100, 'b').unwrap();
+        assert_eq!(merger.push(0, 'd'), Err('d'));
+
+        assert_eq!(merger.pop_ready(500), None);
+        assert_eq!(merger.pop_ready(900), Some((u32::MAX - 100, 'a')));
+        assert_eq!(merger.pop_ready(900), None);
+        assert_eq!(merger.pop_ready(1_200), Some((100, 'b')));
+        assert_eq!(merger.pop_ready(1_200), Some((200, 'c')));
+        assert_eq!(merger.pop_ready(5_000), None);
+    }
+
+    #[test]
+    fn link_monitor_degrades_then_drops() {
+        let mut monitor = LinkMonitor::new(HealthConfig::default());
+        assert_eq!(monitor.state(), LinkState::Down);
+        assert_eq!(monitor.update(5_000), None);
+
+        assert!(monitor.received(5_000));
+        assert_eq!(monitor.state(), LinkState::Up);
+        assert_eq!(monitor.update(5_299), None);
+        assert_eq!(monitor.update(5_300), Some(LinkState::Degraded));
+        // A late frame brings the link back up without a re-sync
+        assert!(!monitor.received(5_400));
+        assert_eq!(monitor.state(), LinkState::Up);
+        assert_eq!(monitor.update(6_400), Some(LinkState::Down));
+        assert_eq!(monitor.update(7_000), None);
+        assert!(monitor.received(7_000));
+    }
+
+    #[test]
+    fn key_set_releases_pressed_keys() {
+        let mut keys = KeySet::<4, 12>::new();
+        assert!(keys.set(KeyPosition::new(0, 7), true));
+        assert!(!keys.set(KeyPosition::new(0, 7), true));
+        assert!(keys.set(KeyPosition::new(3, 11), true));
+        assert!(!keys.set(KeyPosition::new(4, 0), true));
+        assert!(keys.set(KeyPosition::new(1, 2), true));
+        assert!(keys.set(KeyPosition::new(1, 2), false));
+        assert!(keys.contains(KeyPosition::new(3, 11)));
+        assert!(!keys.contains(KeyPosition::new(1, 2)));
+
+        let mut released = keys.clear();
+        assert_eq!(released.next(), Some(KeyPosition::new(0, 7)));
+        assert_eq!(released.next(), Some(KeyPosition::new(3, 11)));
+        assert_eq!(released.next(), None);
+        assert!(keys.is_empty());
+
+        // Carried by the heartbeats
+        keys.set(KeyPosition::new(1, 3), true);
+        assert_eq!(keys.bits(), 1 << 15);
+        assert_eq!(KeySet::<4, 12>::from_bits(keys.bits()), keys);
+        assert!(KeySet::<4, 12>::from_bits(u64::MAX).contains(KeyPosition::new(3, 11)));
+        assert_eq!(KeySet::<4, 12>::from_bits(u64::MAX).bits(), (1 << 48) - 1);
+    }
+
+    const DIAGNOSTICS: DiagnosticConfig = DiagnosticConfig {
+        boot_ms: 500,
+        chatter_ms: 20,
+        combo: &[KeyPosition::new(0, 0), KeyPosition::new(0, 11)],
+    };
+
+    #[test]
+    fn diagnostics_report_new_keys_and_chatter() {
+        let mut diagnostics = Diagnostics::<4, 12>::new(DIAGNOSTICS);
+        assert_eq!(
+            diagnostics.update(key(2, 3, true), 10),
+            Some(Finding::StuckAtBoot(KeyPosition::new(2, 3)))
+        );
+        assert_eq!(diagnostics.update(key(1, 1, true), 1000), None);
+        assert_eq!(diagnostics.update(key(0, 0, true), 1010), None);
+        assert_eq!(
+            diagnostics.update(key(0, 11, true), 1020),
+            Some(Finding::Started)
+        );
+        assert!(diagnostics.is_running());
+        diagnostics.update(key(0, 0, false), 1100);
+        diagnostics.update(key(0, 11, false), 1100);
+
+        diagnostics.update(key(1, 1, false), 1300);
+        assert_eq!(
+            diagnostics.update(key(1, 1, true), 1305),
+            Some(Finding::Chatter(KeyPosition::new(1, 1)))
+        );
+        diagnostics.update(key(1, 1, false), 1310);
+        assert_eq!(
+            diagnostics.update(key(1, 1, true), 1312),
+            Some(Finding::NewKey(KeyPosition::new(1, 1)))
+        );
+        diagnostics.update(key(1, 1, false), 1400);
+        assert_eq!(diagnostics.update(key(1, 1, true), 1500), None);
+        assert_eq!(
+            diagnostics.update(key(3, 7, true), 1600),
+            Some(Finding::NewKey(KeyPosition::new(3, 7)))
+        );
+        assert_eq!(
+            Finding::NewKey(KeyPosition::new(3, 7)).encode()[..4],
+            [diagnostic::REPORT_ID, 2, 3, 7]
+        );
+
+        diagnostics.update(key(0, 0, true), 2000);
+        assert_eq!(
+            diagnostics.update(key(0, 11, true), 2000),
+            Some(Finding::Finished)
+        );
+        assert!(!diagnostics.is_running());
+
+        // The findings are only sent when the host asks for them
+        let request = Command::DumpDiagnostics.encode();
+        assert_eq!(request[0], 0xee);
+        let mut settings: Settings<1, 2, 2> = Settings::new(KEYMAP);
+        let (response, effect) = protocol::handle_request(&request, &mut settings, &KEYMAP);
+        assert_eq!(response[..2], [0xee, 0]);
+        assert_eq!(effect, Effect::DumpDiagnostics);
+    }
+
+    #[test]
+    fn diagnostics_list_untested_keys_per_half() {
+        const COMBO: &[KeyPosition] = &[KeyPosition::new(1, 3)];
+        let mut diagnostics = Diagnostics::<2, 4>::new(DiagnosticConfig {
+            combo: COMBO,
+            ..DIAGNOSTICS
+        });
+        assert_eq!(
+            diagnostics.update(key(1, 3, true), 1000),
+            Some(Finding::Started)
+        );
+        for &(row, col) in &[(0, 0), (1, 1), (0, 3)] {
+            diagnostics.update(key(row, col, true), 1100);
+        }
+
+        let right = HalfMap::<2, 2>::mirrored(2);
+        let mut untested = diagnostics.untested(right.keys());
+        assert_eq!(untested.next(), Some(KeyPosition::new(0, 2)));
+        assert_eq!(untested.next(), Some(KeyPosition::new(1, 3)));
+        assert_eq!(untested.next(), Some(KeyPosition::new(1, 2)));
+        assert_eq!(untested.next(), None);
+
+        let report = diagnostics.untested_report(Side::Right, right.keys());
+        assert_eq!(
+            report[..5],
+            [
+                diagnostic::REPORT_ID,
+                5,
+                Side::Right.to_byte(),
+                3,
+                0b1100_0100
+            ]
+        );
+    }
+
+    #[test]
+    fn half_map_places_matrix_keys_on_keyboard() {
+        let event = MatrixEvent {
+            position: MatrixPosition::new(2, 3),
+            pressed: true,
+        };
+        assert_eq!(
+            HalfMap::<4, 6>::offset(6).event(event),
+            Some(KeyEvent {
+                position: KeyPosition::new(2, 9),
+                pressed: true
+            })
+        );
+        let mirrored = HalfMap::<4, 6>::mirrored(6);
+        assert_eq!(
+            mirrored.key(MatrixPosition::new(2, 0)),
+            Some(KeyPosition::new(2, 11))
+        );
+        assert_eq!(
+            mirrored.key(MatrixPosition::new(2, 5)),
+            Some(KeyPosition::new(2, 6))
+        );
+        assert_eq!(mirrored.key(MatrixPosition::new(4, 0)), None);
+    }
+
+    #[test]
+    fn half_map_skips_missing_switches_and_moves_thumb_keys() {
+        const THUMBS: HalfMap<2, 3> = HalfMap::new([
+            [
+                Some(KeyPosition::new(0, 0)),
+                Some(KeyPosition::new(0, 1)),
+                None,
+            ],
+            [
+                Some(KeyPosition::new(1, 0)),
+                Some(KeyPosition::new(2, 4)),
+                Some(KeyPosition::new(2, 5)),
+            ],
+        ]);
+        assert_eq!(THUMBS.key(MatrixPosition::new(0, 2)), None);
+        assert_eq!(
+            THUMBS.key(MatrixPosition::new(1, 2)),
+            Some(KeyPosition::new(2, 5))
+        );
+
+        let event = MatrixEvent {
+            position: MatrixPosition::new(0, 2),
+            pressed: true,
+        };
+        assert_eq!(THUMBS.event(event), None);
+        let map = HalfMap::<2, 3>::offset(0)
+            .without(MatrixPosition::new(0, 2))
+            .with(MatrixPosition::new(1, 2), KeyPosition::new(2, 1));
+        assert_eq!(map.event(event), None);
+        assert_eq!(
+            map.key(MatrixPosition::new(1, 2)),
+            Some(KeyPosition::new(2, 1))
+        );
+    }
+
+    #[test]
+    fn led_shows_most_important_state() {
+        let mut indicators = Indicators {
+            layer: 2,
+            caps_lock: true,
+            ..Indicators::default()
+        };
+        let slave = LedConfig {
+            usb: false,
+            caps_lock: false,
+            ..LedConfig::ALL
+        };
+        assert_eq!(
+            LedConfig::ALL.pattern(&indicators),
+            Pattern::Blink {
+                on_ms: 100,
+                period_ms: 1000
+            }
+        );
+
+        indicators.link_up = true;
+        let layer = slave.pattern(&indicators);
+        assert_eq!(layer, Pattern::Flashes { count: 2 });
+        let levels = [0, 149, 150, 300, 450, 600, 1599, 1600].map(|t| layer.is_on(t));
+        assert_eq!(levels, [true, true, false, true, false, false, false, true]);
+
+        indicators.usb_configured = true;
+        assert_eq!(LedConfig::ALL.pattern(&indicators), Pattern::On);
+    }
 }